@@ -1,15 +1,47 @@
-///! Parses command-line arguments and prints help
+//! Parses command-line arguments and prints help
+use crate::cleanup;
+use crate::completions;
+use crate::config;
+use crate::dotenv;
+use crate::event;
+use crate::hours::ActiveHours;
+use crate::patterns;
+use crate::pidfile;
+use crate::pty;
+use crate::runlog::{self, Run};
+use crate::status::{self, status_eprintln, status_println};
+use crate::streams;
+use crate::template;
+use chrono::format::{Item, StrftimeItems};
 use chrono::Duration;
-use log::{debug, error, info, trace, warn};
-use notify::RecursiveMode;
+use log::{debug, error, info, trace, warn, LevelFilter};
+use notify::{Op, RecursiveMode};
 use regex::Regex;
 use std::{
+    collections::BTreeSet,
     fmt::Display,
     fs,
+    io::{IsTerminal, Write},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::JoinHandle,
 };
 
+/// The state of the runs of one watcher's command, shared by the copies of its arguments
+/// that --detach threads, --config-reload and the like make - and kept apart from that of
+/// other `[[rule]]`s of a configuration file
+#[derive(Debug, Default)]
+struct Runs {
+    /// With --detach, how many copies of the command are running
+    detached: (Mutex<usize>, Condvar),
+    /// With --restart, the running command's process id, and whether it is being stopped
+    restarting: (Mutex<Option<(u32, bool)>>, Condvar),
+    /// With --max-failures, how many runs in a row have failed
+    failures: AtomicUsize,
+}
+
 // Grab some info from Cargo.toml to emit in the help:
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
@@ -19,28 +51,49 @@ const REPO: &str = env!("CARGO_PKG_REPOSITORY");
 // Default values
 const DEFAULT_DELAY_SECONDS: usize = 30;
 const DEFAULT_PATH: &str = "./";
+/// The longest duration any option accepts - a hundred years, far longer than any use needs,
+/// but short enough that adding it to the current time cannot overflow
+const MAX_DURATION_SECONDS: i64 = 100 * 365 * 24 * 60 * 60;
+
+/// Separates several values passed to a repeatable option at once
+const LIST_DELIMITER: char = ',';
+
+/// Environment variable which overrides the default delay, in seconds
+const DELAY_ENV: &str = "WATCHFS_DELAY";
 
 // Arguments
 const VERBOSE_SHORT: &str = "-v";
 const VERBOSE_LONG: &str = "--verbose";
+const VERBOSE_LEVEL_PREFIX: &str = "--verbose=";
 
 const HELP_SHORT: &str = "-h";
 const HELP_LONG: &str = "--help";
 
+const HELP_JSON_LONG: &str = "--help-json";
+
+// Hidden - used by packagers, so not listed in the help
+const COMPLETIONS_LONG: &str = "--completions";
+
 const RELATIVIZE_SHORT: &str = "-r";
 const RELATIVIZE_LONG: &str = "--relativize";
+const SLASH_LONG: &str = "--slash";
 
 const ONCE_SHORT: &str = "-o";
 const ONCE_LONG: &str = "--once";
 
 const PASS_CHANGED_PATHS_SHORT: &str = "-p";
 const PASS_CHANGED_PATHS_LONG: &str = "--pass-paths";
+const PASS_DIR_LONG: &str = "--pass-dir";
 
 const SHELL_SHORT: &str = "-l";
 const SHELL_LONG: &str = "--shell";
+const RAW_SHELL_LONG: &str = "--raw-shell";
 
 const NON_RECURSIVE_SHORT: &str = "-n";
 const NON_RECURSIVE_LONG: &str = "--non-recursive";
+const ONE_FILESYSTEM_LONG: &str = "--one-filesystem";
+const OWNER_LONG: &str = "--owner";
+const PRUNE_DIR_GLOB_LONG: &str = "--prune-dir-glob";
 
 const FILTER_SHORT: &str = "-f";
 const FILTER_LONG: &str = "--filter";
@@ -50,38 +103,481 @@ const SECONDS_LONG: &str = "--seconds";
 
 const DIR_SHORT: &str = "-d";
 const DIR_LONG: &str = "--dir";
+const NO_CANONICALIZE_LONG: &str = "--no-canonicalize";
 
 const EXIT_ON_ERROR_SHORT: &str = "-x";
 const EXIT_ON_ERROR_LONG: &str = "--exit-on-error";
+const MAX_FAILURES_LONG: &str = "--max-failures";
+const SUCCESS_CODES_LONG: &str = "--success-codes";
+
+const PRESERVE_ORDER_LONG: &str = "--preserve-order";
+
+const FAILURE_COOLDOWN_LONG: &str = "--failure-cooldown";
+
+const STDIN_TRIGGER_LONG: &str = "--stdin-trigger";
+
+const PAUSE_ON_ERROR_LONG: &str = "--pause-on-error";
+
+const CONFIRM_LONG: &str = "--confirm";
+const CONFIRM_TIMEOUT_LONG: &str = "--confirm-timeout";
+const DEFAULT_CONFIRM_TIMEOUT_SECONDS: i64 = 30;
+
+const INCLUDE_CHMOD_LONG: &str = "--include-chmod";
+const CHMOD_LONG: &str = "--chmod";
+
+const REWATCH_GRACE_LONG: &str = "--rewatch-grace";
+
+const PTY_LONG: &str = "--pty";
+
+const DIRS_ONLY_LONG: &str = "--dirs-only";
+
+const COMMAND_TEMPLATE_LONG: &str = "--command-template";
+const COMMAND_JSON_LONG: &str = "--command-json";
+
+const EXPAND_ENV_LONG: &str = "--expand-env";
+
+const HEARTBEAT_LONG: &str = "--heartbeat";
+
+const JITTER_LONG: &str = "--jitter";
+const FAST_FIRST_LONG: &str = "--fast-first";
+const SETTLE_RATE_LONG: &str = "--settle-rate";
+const JITTER_SEED_LONG: &str = "--jitter-seed";
+
+const KILL_CHILDREN_ON_EXIT_LONG: &str = "--kill-children-on-exit";
+const CHILD_PIDFILE_LONG: &str = "--child-pidfile";
+const ENV_LONG: &str = "--env";
+const ENV_FILE_LONG: &str = "--env-file";
+const CLEAN_ENV_LONG: &str = "--clean-env";
+const SCRATCH_DIR_LONG: &str = "--scratch-dir";
+const SCRATCH_ENV: &str = "WATCHFS_SCRATCH";
+const SCRATCH_TOKEN: &str = "{scratch}";
+const UMASK_LONG: &str = "--umask";
+
+const RUST_LONG: &str = "--rust";
+
+const CONFIG_LONG: &str = "--config";
+const CONFIG_RELOAD_LONG: &str = "--config-reload";
+const GRACEFUL_RESTART_LONG: &str = "--graceful-restart-on-config-change";
+const INIT_LONG: &str = "--init";
+const FORCE_LONG: &str = "--force";
+
+const BY_DIR_LONG: &str = "--by-dir";
+const CANONICAL_PATHS_LONG: &str = "--canonical-paths";
+const KEEP_MISSING_LONG: &str = "--keep-missing";
+const EXISTING_ONLY_LONG: &str = "--existing-only";
+const DELETIONS_ONLY_LONG: &str = "--deletions-only";
+
+const PRINT_WATCHED_PATHS_LONG: &str = "--print-watched-paths";
+const DEBUG_FILTER_LONG: &str = "--debug-filter";
+
+const FLUSH_ON_LONG: &str = "--flush-on";
+const EXIT_ON_LONG: &str = "--exit-on";
+const WORKING_SET_LIMIT_LONG: &str = "--working-set-limit";
+const MIN_PATHS_LONG: &str = "--min-paths";
+const DROP_BELOW_MIN_PATHS_LONG: &str = "--drop-below-min-paths";
+const ACTIVE_HOURS_LONG: &str = "--active-hours";
+const DROP_OUTSIDE_ACTIVE_HOURS_LONG: &str = "--drop-outside-active-hours";
+const POLL_FALLBACK_LONG: &str = "--poll-fallback";
+const POLL_PATH_LONG: &str = "--poll-path";
+
+const TAG_STREAMS_LONG: &str = "--tag-streams";
+const TIMESTAMP_OUTPUT_LONG: &str = "--timestamp-output";
+const TIMESTAMP_FORMAT_LONG: &str = "--timestamp-format";
+/// ISO 8601, to the millisecond, in local time
+const DEFAULT_TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f%:z";
+
+const MAX_EVENTS_PER_SECOND_LONG: &str = "--max-events-per-second";
+
+const EMIT_EMPTY_LONG: &str = "--emit-empty";
+
+const WATCH_LIST_LONG: &str = "--watch-list";
+const ALSO_WATCH_LONG: &str = "--also-watch";
+
+const STDIN_FILE_LONG: &str = "--stdin-file";
+
+const RESOLVE_SYMLINK_TARGETS_LONG: &str = "--resolve-symlink-targets";
+
+const MAX_OUTPUT_BYTES_LONG: &str = "--max-output-bytes";
+
+/// Paths under the watched directory the --rust preset limits changes to
+const RUST_PRESET_PATHS: &[&str] = &["src", "tests", "Cargo.toml", "Cargo.lock"];
+/// Directory under the watched directory the --rust preset ignores changes in
+const RUST_PRESET_EXCLUDE: &str = "target";
+/// Filter regex the --rust preset applies if no -f/--filter is passed
+const RUST_PRESET_FILTER: &str = r"\.rs$|Cargo\.(toml|lock)$";
+
+const RECORD_LONG: &str = "--record";
+const REPLAY_EVENTS_LONG: &str = "--replay-events";
+const REPLAY_SPEED_LONG: &str = "--replay-speed";
+
+const LISTEN_LONG: &str = "--listen";
+const JSON_FILE_LONG: &str = "--json-file";
+const FIFO_LONG: &str = "--fifo";
+const MIRROR_TO_LONG: &str = "--mirror-to";
+const NO_RUN_LONG: &str = "--no-run";
+
+const JSON_LONG: &str = "--json";
+const JSON_PRETTY_LONG: &str = "--json-pretty";
+
+const INCLUDE_FROM_LONG: &str = "--include-from";
+const EXCLUDE_FROM_LONG: &str = "--exclude-from";
+const PATTERN_SYNTAX_LONG: &str = "--pattern-syntax";
+const FILTER_BASENAME_LONG: &str = "--filter-basename";
+const FILTER_COMPONENT_LONG: &str = "--filter-component";
+
+const MAX_WATCHERS_LONG: &str = "--max-watchers";
+
+const DEBOUNCE_FOR_LONG: &str = "--debounce-for";
+
+const WARM_UP_LONG: &str = "--warm-up";
+const WARM_UP_INTERVAL_LONG: &str = "--warm-up-interval";
+const WARM_UP_ATTEMPTS_LONG: &str = "--warm-up-attempts";
+const DEFAULT_WARM_UP_INTERVAL_SECONDS: i64 = 1;
+const DEFAULT_WARM_UP_ATTEMPTS: usize = 30;
+
+const PATHS_AS_RESPONSE_FILE_LONG: &str = "--paths-as-response-file";
+const RESPONSE_FILE_PREFIX_LONG: &str = "--response-file-prefix";
+const DEFAULT_RESPONSE_FILE_PREFIX: &str = "@";
+
+const AFTER_QUIET_ONLY_LONG: &str = "--after-quiet-only";
+const STABLE_FOR_LONG: &str = "--stable-for";
+const STABLE_POLL_LONG: &str = "--stable-poll";
+const DEFAULT_STABLE_POLL_MILLIS: i64 = 250;
+const SKIP_DUPLICATE_BATCHES_LONG: &str = "--skip-duplicate-batches";
+const SHOW_TIMING_LONG: &str = "--show-timing";
+const ANNOUNCE_LONG: &str = "--announce";
+
+const DETACH_LONG: &str = "--detach";
+const RESTART_LONG: &str = "--restart";
+const STOP_SIGNAL_LONG: &str = "--stop-signal";
+const STOP_TIMEOUT_LONG: &str = "--stop-timeout";
+const DEFAULT_STOP_TIMEOUT_SECONDS: i64 = 10;
+const MAX_DETACHED_LONG: &str = "--max-detached";
+
+const TOUCH_ON_SUCCESS_LONG: &str = "--touch-on-success";
+const TOUCH_ON_FAILURE_LONG: &str = "--touch-on-failure";
+const BELL_ON_ERROR_LONG: &str = "--bell-on-error";
+const BELL_ON_SUCCESS_LONG: &str = "--bell-on-success";
+
+const ON_CREATE_LONG: &str = "--on-create";
+const ON_MODIFY_LONG: &str = "--on-modify";
+const ON_DELETE_LONG: &str = "--on-delete";
+
+const STATUS_TO_LONG: &str = "--status-to";
+const RUN_LOG_LONG: &str = "--run-log";
+const STATUS_FILE_PREFIX: &str = "file:";
+const JSON_SCHEMA_VERSION_LONG: &str = "--output-json-schema-version";
 
 #[derive(Debug, Clone)]
 pub(crate) struct Args {
     /// Whether or not to do some logging straight to stderr
     pub verbose: bool,
+    /// How many times -v was passed (or the value of --verbose=n), which sets the log level
+    verbosity: u8,
     /// Whether to print help to stdout and exit immediately
     help: bool,
+    /// Whether to print a JSON description of our options to stdout and exit immediately
+    help_json: bool,
     /// The file path - default is the working directory
     pub path: String,
+    /// The -d path as it was passed, before canonicalization
+    given_path: Option<String>,
+    /// If true, watch the directory by the path given rather than its canonical path
+    no_canonicalize: bool,
+    /// The watched directory as given, made absolute with any symlinks in it kept
+    literal_dir: PathBuf,
+    /// The watched directory with any symlinks in it resolved
+    canonical_dir: PathBuf,
     /// The number of seconds of quiescence needed before we publish/run the command
     pub delay_seconds: usize,
     /// If true, pass the set of changed paths as arguments to the command process
     pub pass_changed_paths: bool,
+    /// If true, pass the watched directory as the command's first argument after its own
+    pass_dir: bool,
     /// If true, relative the paths to the value of self.path when passing them to
     /// the command process
     pub relativize_paths: bool,
+    /// The separator to put in paths passed to the command, if not the platform's own -
+    /// `/` or `\`
+    slash: Option<char>,
     /// If true, spawn a shell to run the command in rather than exec'ing it directly
     shell: bool,
     /// If true, exit on any encountered error, including non-zero returns
     pub exit_on_error: bool,
+    /// If set, exit once the command has failed this many times in a row
+    max_failures: Option<usize>,
+    /// Exit codes of the command which count as success - if empty, only 0
+    success_codes: BTreeSet<i32>,
     /// If true, exit after the first successful invocation of the command
     once: bool,
     /// The command and arguments to run
     command: Vec<String>,
     /// If true, don't listen recursively, only listen to files directly in the target folder
     pub non_recursive: bool,
-    /// A regex to filter out file changes we don't care about.  It is passed the *fully qualified*
-    /// file name
-    filter: Option<Regex>,
+    /// If true, do not watch directories beneath the target on other filesystems, like
+    /// `find -xdev`
+    pub one_filesystem: bool,
+    /// With --owner, only changes to files owned by this user id count
+    owner: Option<u32>,
+    /// Directories matching any of these --prune-dir-glob globs, and everything beneath them,
+    /// are never watched
+    prune_dirs: Vec<Regex>,
+    /// Regexes to filter out file changes we don't care about - a path matching any of them
+    /// is accepted.  They are passed the *fully qualified* file name
+    filter: Vec<Regex>,
+    /// If true, pass changed paths in the order they were first changed, rather than sorted
+    pub preserve_order: bool,
+    /// If true, print a JSON record of each batch of changes to stdout
+    pub json: bool,
+    /// If set, after the command fails, do not run it again until this much time has passed
+    pub failure_cooldown: Option<Duration>,
+    /// If true, read lines from stdin, running the command immediately on each empty one
+    pub stdin_trigger: bool,
+    /// If true, permission-only changes to files count as changes
+    pub chmod_files: bool,
+    /// If true, permission-only changes to directories count as changes
+    pub chmod_dirs: bool,
+    /// If set, re-watch the target directory if it is replaced, after waiting this long
+    pub rewatch_grace: Option<Duration>,
+    /// If true, run the command attached to a pseudo-terminal
+    pty: bool,
+    /// If true, only changes to paths which are directories count
+    dirs_only: bool,
+    /// The text of a template rendered to produce a shell command for each batch
+    command_template: Option<String>,
+    /// If true, stop running the command after it fails, until Enter is pressed
+    pub pause_on_error: bool,
+    /// If true, ask at the terminal before each run of the command
+    pub confirm: bool,
+    /// With --confirm, how long to wait for an answer before taking it as no
+    pub confirm_timeout: Duration,
+    /// If true, expand environment variables in the command's arguments when not using a shell
+    expand_env: bool,
+    /// If set, print a line to stderr whenever this long passes with no events
+    pub heartbeat: Option<Duration>,
+    /// If set, add a random amount of time up to this to the delay before running the command
+    pub jitter: Option<Duration>,
+    /// If set, the delay for the first batch of changes after startup, if shorter
+    pub fast_first: Option<Duration>,
+    /// If set, run a batch as soon as fewer than this many changes arrive per second, rather
+    /// than waiting for the delay
+    pub settle_rate: Option<usize>,
+    /// Seed for the --jitter offsets, so they are the same on every run
+    pub jitter_seed: Option<u64>,
+    /// If set, append every raw event received to this file as JSON lines
+    pub record: Option<String>,
+    /// If set, read events from this file (as written by --record) instead of watching
+    pub replay_events: Option<String>,
+    /// How fast to replay --replay-events events relative to when they were recorded - zero
+    /// for all at once
+    pub replay_speed: f64,
+    /// If set, serve each batch as JSON to clients connected to this address or socket path
+    pub listen: Option<String>,
+    /// If set, append the JSON record of each batch to this file
+    pub json_file: Option<String>,
+    /// If set, write the JSON record of each batch to this named pipe, creating it if needed
+    pub fifo: Option<PathBuf>,
+    /// If set, copy each changed file to the same place under this directory, and remove the
+    /// copies of deleted ones
+    pub mirror_to: Option<PathBuf>,
+    /// If true, never run a command
+    no_run: bool,
+    /// If true, terminate the running command (and its process group) when watchfs exits
+    kill_children_on_exit: bool,
+    /// If set, keep the process id of the running command in this file
+    child_pidfile: Option<PathBuf>,
+    /// If true, apply the defaults for Rust projects once other arguments are parsed
+    rust: bool,
+    /// If non-empty, only changes to these paths or files beneath them count
+    only_under: Vec<PathBuf>,
+    /// Changes to these paths or files beneath them never count
+    excluded: Vec<PathBuf>,
+    /// A configuration file to read default arguments from, instead of searching for one
+    config: Option<String>,
+    /// If true, list the directories being watched to stderr on startup
+    pub print_watched_paths: bool,
+    /// If true, print whether each event counts as a change, and which rule decided it
+    debug_filter: bool,
+    /// If set, a change to this file runs the command immediately, without waiting
+    pub flush_on: Option<PathBuf>,
+    /// If set, a change to this file runs any pending changes, waits for the command and exits
+    pub exit_on: Option<PathBuf>,
+    /// If true, prefix each line the command writes with the stream it was written to
+    tag_streams: bool,
+    /// If true, prefix each line of the command's output with the time it was read
+    timestamp_output: bool,
+    /// The strftime format of --timestamp-output timestamps, if not the default
+    timestamp_format: Option<String>,
+    /// If set, changes beyond this many in a second are dropped
+    pub max_events_per_second: Option<usize>,
+    /// Run the command without waiting for the delay once this many changed paths are pending
+    pub working_set_limit: Option<usize>,
+    /// If set, only run the command once a batch has at least this many changed paths
+    pub min_paths: Option<usize>,
+    /// If true, drop batches smaller than --min-paths rather than keeping them for the next
+    pub drop_below_min_paths: bool,
+    /// If set, only run the command for batches which become due within these hours of the day
+    pub active_hours: Option<ActiveHours>,
+    /// If true, drop batches which become due outside --active-hours rather than keeping them
+    /// until the window opens
+    pub drop_outside_active_hours: bool,
+    /// How often to check that the native watcher still reports changes, switching to polling
+    /// if it does not
+    pub poll_fallback: Option<Duration>,
+    /// Directories beneath the watched one to poll for changes, as well as watching natively
+    pub poll_paths: Vec<PathBuf>,
+    /// If true, run the command whenever the delay elapses, even if no changed paths remain
+    pub emit_empty: bool,
+    /// If non-empty, watch only these files, rather than everything in the target directory
+    pub watch_list: Vec<PathBuf>,
+    /// Files and directories to watch as well as the target directory, with whether each is a
+    /// directory
+    also_watch: Vec<(PathBuf, bool)>,
+    /// If set, a file to connect to the command's stdin
+    stdin_file: Option<String>,
+    /// If true, also watch the targets of symlinks in the tree which point outside it
+    pub resolve_symlink_targets: bool,
+    /// If set, pass on at most this many bytes of the command's output per run
+    max_output_bytes: Option<usize>,
+    /// If true, indent the --json record over several lines
+    pub json_pretty: bool,
+    /// If true, the filter regexes are matched against the file name rather than the path
+    filter_basename: bool,
+    /// If true, a filter regex accepts a path if it matches the whole of any component of the
+    /// path relative to the watched directory; overrides filter_basename
+    filter_component: bool,
+    /// If set, exit on startup if more than this many directories would be watched
+    pub max_watchers: Option<usize>,
+    /// Delays to use instead of -s for changes to files with particular extensions
+    debounce_for: Vec<(String, Duration)>,
+    /// Where watchfs's own messages go - stdout, stderr, file:<path> or null
+    status_to: Option<String>,
+    /// A file to append a JSON record of the outcome of each run of the command to
+    run_log: Option<String>,
+    /// A shell command to run until it succeeds before watching starts
+    warm_up: Option<String>,
+    /// How long to wait between attempts to run the warm-up command
+    warm_up_interval: Duration,
+    /// How many times to try the warm-up command before giving up
+    warm_up_attempts: usize,
+    /// If true, pass the changed paths in a temporary file, named by a single argument
+    paths_as_response_file: bool,
+    /// What precedes the name of the --paths-as-response-file file in the argument
+    response_file_prefix: String,
+    /// If set, before running, wait until nothing at all has been reported for this long
+    pub after_quiet_only: Option<Duration>,
+    /// If set, before running, wait until no changed file has changed size or modification
+    /// time for this long
+    pub stable_for: Option<Duration>,
+    /// How often to look at the changed files while waiting for them to be stable, if not the
+    /// default
+    stable_poll: Option<Duration>,
+    /// If set, do not run the command for a batch of the same paths as the last successful
+    /// run, if it was within this long
+    pub skip_duplicate_batches: Option<Duration>,
+    /// If true, print how long each batch waited since its first change before running
+    pub show_timing: bool,
+    /// If true, print a one-line summary of the command and changed paths before each run
+    pub announce: bool,
+    /// If true, do not wait for the command to exit before collecting the next batch
+    detach: bool,
+    /// With --detach, the most copies of the command which may run at once
+    max_detached: Option<usize>,
+    /// A file to create or update the modification time of when the command succeeds
+    pub touch_on_success: Option<PathBuf>,
+    /// A file to create or update the modification time of when the command fails
+    pub touch_on_failure: Option<PathBuf>,
+    /// Environment variables to set for the command
+    env: Vec<(String, String)>,
+    /// Dotenv files of environment variables to set for the command, which --env overrides
+    env_files: Vec<String>,
+    /// If true, the command inherits no environment variables, only those from --env
+    clean_env: bool,
+    /// If true, create an empty directory for each run of the command, removed when it exits
+    scratch_dir: bool,
+    /// With --scratch-dir, the directory for the run being started
+    scratch: Option<PathBuf>,
+    /// The umask to run the command with, on unix
+    umask: Option<u32>,
+    /// If true, ring the terminal bell when the command fails
+    bell_on_error: bool,
+    /// If true, ring the terminal bell when the command succeeds
+    bell_on_success: bool,
+    /// If true, re-read the configuration file when it changes
+    pub config_reload: bool,
+    /// If true, a reloaded configuration may also change what is watched, and the watcher is
+    /// rebuilt when it does
+    restart_on_config_change: bool,
+    /// If true, write a configuration file template to the working directory and exit
+    init: bool,
+    /// With --init, replace any existing configuration file
+    force: bool,
+    /// The configuration file arguments were read from, if any
+    pub config_file: Option<PathBuf>,
+    /// If true, pass the directories containing changed files rather than the files
+    pub by_dir: bool,
+    /// If true, pass each changed file once, by its canonical path
+    pub canonical_paths: bool,
+    /// With --canonical-paths, pass paths which no longer exist rather than dropping them
+    pub keep_missing: bool,
+    /// If true, pass only changed paths which still exist when the command runs
+    pub existing_only: bool,
+    /// If true, pass only changed paths which no longer exist when the command runs
+    pub deletions_only: bool,
+    /// If true, stop the command if it is still running when the next batch is ready, and
+    /// start it again
+    restart: bool,
+    /// With --restart, the signal sent to stop the command
+    stop_signal: i32,
+    /// With --restart, how long to wait after signalling the command before killing it
+    stop_timeout: Duration,
+    /// Files of patterns for paths to accept, in addition to any -f regexes
+    include_from: Vec<String>,
+    /// Files of patterns for paths to ignore regardless of other filters
+    exclude_from: Vec<String>,
+    /// If true, the lines of pattern files are regexes rather than globs
+    pattern_regex: bool,
+    /// The patterns compiled from the --include-from files
+    includes: Vec<Regex>,
+    /// The patterns compiled from the --exclude-from files
+    excludes: Vec<Regex>,
+    /// A shell command to run for paths which were created, instead of the command
+    on_create: Option<String>,
+    /// A shell command to run for paths which were modified, instead of the command
+    on_modify: Option<String>,
+    /// A shell command to run for paths which were deleted, instead of the command
+    on_delete: Option<String>,
+    /// If true, the words of the command are joined into the shell command line as they are,
+    /// rather than each being quoted - for --raw-shell, and the --on-* commands, which already
+    /// are one
+    raw_shell: bool,
+    /// Which `[[rule]]` of the configuration file these arguments are for, if any
+    rule: Option<usize>,
+    runs: Arc<Runs>,
+}
+
+/// What happened to a path in a batch of changes, which picks the --on-* command it goes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Change {
+    Create,
+    Modify,
+    Delete,
+}
+
+impl Change {
+    /// Classify the combined operations reported for a path in a batch - a path which is gone
+    /// was deleted, even if it was also created or written first; one which exists was created
+    /// if it was created or renamed into place at any point, even if also written or removed
+    /// and re-created; anything else was modified
+    pub(crate) fn of(op: Op, exists: bool) -> Change {
+        if !exists && op.intersects(Op::REMOVE | Op::RENAME) {
+            Change::Delete
+        } else if op.intersects(Op::CREATE | Op::RENAME) {
+            Change::Create
+        } else {
+            Change::Modify
+        }
+    }
 }
 
 /// Provides reasonable default values
@@ -89,17 +585,139 @@ impl Default for Args {
     fn default() -> Args {
         Args {
             verbose: false,
+            verbosity: 0,
             path: String::from(DEFAULT_PATH),
+            given_path: None,
+            no_canonicalize: false,
+            literal_dir: PathBuf::new(),
+            canonical_dir: PathBuf::new(),
             help: false,
+            help_json: false,
             delay_seconds: DEFAULT_DELAY_SECONDS,
             pass_changed_paths: true,
+            pass_dir: false,
             command: vec![],
             relativize_paths: false,
+            slash: None,
             shell: false,
             exit_on_error: false,
+            max_failures: None,
+            success_codes: BTreeSet::new(),
             once: false,
             non_recursive: false,
-            filter: None,
+            one_filesystem: false,
+            owner: None,
+            prune_dirs: Vec::new(),
+            filter: vec![],
+            preserve_order: false,
+            json: false,
+            failure_cooldown: None,
+            stdin_trigger: false,
+            chmod_files: false,
+            chmod_dirs: false,
+            rewatch_grace: None,
+            pty: false,
+            dirs_only: false,
+            command_template: None,
+            pause_on_error: false,
+            expand_env: false,
+            heartbeat: None,
+            jitter: None,
+            fast_first: None,
+            settle_rate: None,
+            jitter_seed: None,
+            record: None,
+            replay_events: None,
+            replay_speed: 0.0,
+            listen: None,
+            json_file: None,
+            fifo: None,
+            mirror_to: None,
+            no_run: false,
+            kill_children_on_exit: false,
+            child_pidfile: None,
+            rust: false,
+            only_under: vec![],
+            excluded: vec![],
+            config: None,
+            print_watched_paths: false,
+            debug_filter: false,
+            flush_on: None,
+            exit_on: None,
+            tag_streams: false,
+            timestamp_output: false,
+            timestamp_format: None,
+            max_events_per_second: None,
+            working_set_limit: None,
+            min_paths: None,
+            drop_below_min_paths: false,
+            active_hours: None,
+            drop_outside_active_hours: false,
+            poll_fallback: None,
+            emit_empty: false,
+            watch_list: vec![],
+            also_watch: vec![],
+            stdin_file: None,
+            resolve_symlink_targets: false,
+            max_output_bytes: None,
+            json_pretty: false,
+            filter_basename: false,
+            filter_component: false,
+            max_watchers: None,
+            debounce_for: vec![],
+            status_to: None,
+            warm_up: None,
+            warm_up_interval: Duration::seconds(DEFAULT_WARM_UP_INTERVAL_SECONDS),
+            warm_up_attempts: DEFAULT_WARM_UP_ATTEMPTS,
+            paths_as_response_file: false,
+            response_file_prefix: DEFAULT_RESPONSE_FILE_PREFIX.to_string(),
+            after_quiet_only: None,
+            stable_for: None,
+            stable_poll: None,
+            detach: false,
+            max_detached: None,
+            touch_on_success: None,
+            touch_on_failure: None,
+            env: vec![],
+            env_files: vec![],
+            clean_env: false,
+            scratch_dir: false,
+            scratch: None,
+            umask: None,
+            bell_on_error: false,
+            bell_on_success: false,
+            config_reload: false,
+            restart_on_config_change: false,
+            config_file: None,
+            by_dir: false,
+            canonical_paths: false,
+            keep_missing: false,
+            existing_only: false,
+            deletions_only: false,
+            restart: false,
+            // SIGTERM
+            stop_signal: cleanup::SIGNALS[0].1,
+            stop_timeout: Duration::seconds(DEFAULT_STOP_TIMEOUT_SECONDS),
+            include_from: vec![],
+            exclude_from: vec![],
+            pattern_regex: false,
+            includes: vec![],
+            excludes: vec![],
+            on_create: None,
+            on_modify: None,
+            on_delete: None,
+            run_log: None,
+            poll_paths: vec![],
+            skip_duplicate_batches: None,
+            show_timing: false,
+            announce: false,
+            init: false,
+            force: false,
+            confirm: false,
+            confirm_timeout: Duration::seconds(DEFAULT_CONFIRM_TIMEOUT_SECONDS),
+            raw_shell: false,
+            rule: None,
+            runs: Arc::default(),
         }
     }
 }
@@ -110,22 +728,458 @@ impl Args {
         PathBuf::from(&self.path)
     }
 
+    /// How often to look at the changed files while waiting for them to be stable
+    pub fn stable_poll(&self) -> Duration {
+        self.stable_poll
+            .unwrap_or_else(|| Duration::milliseconds(DEFAULT_STABLE_POLL_MILLIS))
+    }
+
     #[inline]
     pub fn delay(&self) -> Duration {
         Duration::seconds(self.delay_seconds as i64)
     }
 
-    #[inline]
-    pub fn accepts(&self, path: &Path) -> bool {
-        if let Some(rex) = &self.filter {
-            if let Some(st) = path.to_str() {
-                rex.is_match(st)
+    /// The delay for a change to this path - from --debounce-for if its extension has one
+    pub fn delay_for(&self, path: &Path) -> Duration {
+        path.extension()
+            .and_then(|ext| self.debounce_for.iter().find(|(e, _)| ext == e.as_str()))
+            .map(|(_, delay)| *delay)
+            .unwrap_or_else(|| self.delay())
+    }
+
+    /// The longest delay any change can have
+    pub fn longest_delay(&self) -> Duration {
+        self.debounce_for
+            .iter()
+            .map(|(_, delay)| *delay)
+            .fold(self.delay(), Duration::max)
+    }
+
+    /// The single decision of whether a raw event should count as a change
+    pub fn should_handle(&self, path: &Path, op: Op) -> bool {
+        let verdict = self.verdict(path, op);
+        match &verdict {
+            Ok(why) => trace!("Accept {:?} for {:?}: {}", op, path, why),
+            Err(why) => debug!("Ignore {:?} for {:?}: {}", op, path, why),
+        }
+        if self.debug_filter {
+            match &verdict {
+                Ok(why) => status_eprintln!("accept {} ({:?}): {}", path.display(), op, why),
+                Err(why) => status_eprintln!("reject {} ({:?}): {}", path.display(), op, why),
+            }
+        }
+        verdict.is_ok()
+    }
+
+    /// Whether a raw event counts as a change, and the rule which decided it
+    fn verdict(&self, path: &Path, op: Op) -> Result<String, String> {
+        // There are a couple of events we don't care about, unless asked to:
+        if op == Op::RESCAN {
+            return Err(String::from("rescan events are never changes"));
+        }
+        if op == Op::CHMOD && !(self.chmod_files && self.chmod_dirs) {
+            // Only look at the disk if the answer depends on it
+            let wanted = self.chmod_files || self.chmod_dirs;
+            if !wanted || path.is_dir() != self.chmod_dirs {
+                return Err(format!(
+                    "permission-only change, not counted without {} or {}",
+                    INCLUDE_CHMOD_LONG, CHMOD_LONG
+                ));
+            }
+        }
+        if let Some(ex) = self.excluded.iter().find(|ex| path.starts_with(ex)) {
+            return Err(format!("beneath excluded directory {}", ex.display()));
+        }
+        // Nor must copying changes into a mirror in the watched tree
+        if self.mirror_to.iter().any(|mirror| path.starts_with(mirror)) {
+            return Err(format!("in the {} directory", MIRROR_TO_LONG));
+        }
+        if path.parent().is_some_and(|dir| self.is_pruned(dir)) {
+            return Err(format!("beneath a {} directory", PRUNE_DIR_GLOB_LONG));
+        }
+        if self.one_filesystem && self.on_other_filesystem(path) {
+            return Err(format!("on another filesystem ({})", ONE_FILESYSTEM_LONG));
+        }
+        if let Some(uid) = self.owner {
+            // A path which no longer exists has no owner to go by, so a deletion always counts
+            if let Some(owner) = owner_of(path).filter(|owner| *owner != uid) {
+                return Err(format!(
+                    "owned by user {}, not {} ({})",
+                    owner, uid, OWNER_LONG
+                ));
+            }
+        }
+        // Touching a marker in the watched tree must not trigger another run
+        if [&self.touch_on_success, &self.touch_on_failure]
+            .iter()
+            .any(|marker| marker.as_deref() == Some(path))
+        {
+            return Err(format!(
+                "a {} or {} marker file",
+                TOUCH_ON_SUCCESS_LONG, TOUCH_ON_FAILURE_LONG
+            ));
+        }
+        if let Some(verdict) = self.also_watched(path) {
+            return verdict
+                .map(|why| format!("outside the tree, but {}: {}", ALSO_WATCH_LONG, why))
+                .map_err(|why| format!("outside the tree: {}", why));
+        }
+        // --config-reload watches the configuration file's directory, which a [[rule]] may
+        // well be beneath rather than in
+        let config_dir = self.config_file.as_deref().and_then(Path::parent);
+        if self.config_reload && path.parent() == config_dir && !path.starts_with(self.dir()) {
+            return Err(String::from(
+                "outside the tree, beside the configuration file",
+            ));
+        }
+        if !self.watch_list.is_empty() && !self.watch_list.iter().any(|p| p == path) {
+            return Err(format!("not in the {} file", WATCH_LIST_LONG));
+        }
+        if !self.only_under.is_empty() && !self.only_under.iter().any(|p| path.starts_with(p)) {
+            return Err(format!("not beneath any of the {} source paths", RUST_LONG));
+        }
+        if self.dirs_only && !path.is_dir() {
+            return Err(format!("not a directory ({})", DIRS_ONLY_LONG));
+        }
+        self.filter_verdict(path)
+    }
+
+    /// With --one-filesystem, whether a path in the tree is on a different device than the
+    /// watched directory - judged by its nearest existing ancestor if it has been deleted;
+    /// --poll-path directories are exempt, having been asked for
+    fn on_other_filesystem(&self, path: &Path) -> bool {
+        let dir = self.dir();
+        if !path.starts_with(&dir) || self.poll_paths.iter().any(|p| path.starts_with(p)) {
+            return false;
+        }
+        let device = |path: &Path| path.ancestors().find_map(device_of);
+        device(path) != device(&dir)
+    }
+
+    /// Whether recursive watches are made a directory at a time, for --one-filesystem or
+    /// --prune-dir-glob, rather than left to the watcher
+    pub(crate) fn watches_dir_by_dir(&self) -> bool {
+        self.one_filesystem || !self.prune_dirs.is_empty()
+    }
+
+    /// Whether --prune-dir-glob keeps a directory, and everything beneath it, from being watched
+    pub(crate) fn is_pruned(&self, dir: &Path) -> bool {
+        if self.prune_dirs.is_empty() {
+            return false;
+        }
+        let relative = self.pattern_subject(dir);
+        self.prune_dirs.iter().any(|rex| rex.is_match(&relative))
+    }
+
+    /// A path as pattern files and --prune-dir-glob globs see it - relative to the watched
+    /// directory, separated by `/`
+    fn pattern_subject(&self, path: &Path) -> String {
+        let relative = path.strip_prefix(self.dir()).unwrap_or(path).to_string_lossy();
+        if cfg!(windows) {
+            relative.replace('\\', "/")
+        } else {
+            relative.into_owned()
+        }
+    }
+
+    /// Whether -f and the pattern files accept a path, and which of them decided it
+    fn filter_verdict(&self, path: &Path) -> Result<String, String> {
+        let subject = if self.filter_basename {
+            path.file_name().map(Path::new)
+        } else {
+            Some(path)
+        };
+        let relative = self.pattern_subject(path);
+        if let Some(rex) = self.excludes.iter().find(|rex| rex.is_match(&relative)) {
+            Err(format!("{} pattern {} matches {}", EXCLUDE_FROM_LONG, rex, relative))
+        } else if let Some(rex) = self.includes.iter().find(|rex| rex.is_match(&relative)) {
+            Ok(format!("{} pattern {} matches {}", INCLUDE_FROM_LONG, rex, relative))
+        } else if self.filter.is_empty() {
+            if self.includes.is_empty() {
+                Ok(String::from("no filters"))
             } else {
-                false
+                Err(format!("no {} pattern matches {}", INCLUDE_FROM_LONG, relative))
+            }
+        } else if self.filter_component {
+            let relative = path.strip_prefix(self.dir()).unwrap_or(path);
+            let components = relative.components().filter_map(|c| match c {
+                std::path::Component::Normal(name) => name.to_str(),
+                _ => None,
+            });
+            for component in components {
+                if let Some(rex) = self.filter.iter().find(|rex| rex.is_match(component)) {
+                    return Ok(format!("-f {} matches component {}", rex, component));
+                }
+            }
+            Err(format!("no -f regex matches a component of {}", relative.display()))
+        } else if let Some(st) = subject.and_then(Path::to_str) {
+            match self.filter.iter().find(|rex| rex.is_match(st)) {
+                Some(rex) => Ok(format!("-f {} matches {}", rex, st)),
+                None => Err(format!("no -f regex matches {}", st)),
             }
         } else {
-            true
+            Err(String::from("not valid UTF-8, so no -f regex can match it"))
+        }
+    }
+
+    /// Settle the watched directory - canonical unless --no-canonicalize - and check it and
+    /// the --poll-path directories, returning an exit code and message on failure
+    fn locate_dir(&mut self) -> Result<(), (i32, String)> {
+        let gone = |e: std::io::Error| (6, format!("Working directory . no longer exists? {:?}", e));
+        let real = fs::canonicalize(self.path.as_str()).map_err(gone)?;
+        let literal = literal_dir(self.given_path.as_deref());
+        if self.no_canonicalize {
+            debug!("Watching {:?} as given, rather than {:?}", literal, real);
+            self.respell(&real, &literal);
+            self.path = literal.to_string_lossy().to_string();
+        } else if DEFAULT_PATH == self.path.as_str() {
+            self.path = real
+                .to_str()
+                .expect("Could not convert path to a string")
+                .to_string();
+        }
+        self.literal_dir = literal;
+        self.canonical_dir = real;
+        if self.watch_list.is_empty() && !self.dir().is_dir() {
+            // The watcher accepts a file, but everything else assumes a directory
+            return Err((
+                6,
+                format!(
+                    "Target folder {} is not a directory - to watch individual files, use {}",
+                    self.path, WATCH_LIST_LONG
+                ),
+            ));
+        }
+        if let Some(dir) = self.poll_paths.iter().find(|p| !p.starts_with(self.dir())) {
+            // Changed paths are expected to be beneath the watched directory
+            return Err((
+                14,
+                format!(
+                    "{} {} is not beneath the watched directory {}",
+                    POLL_PATH_LONG,
+                    dir.display(),
+                    self.path
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Read and compile the --include-from and --exclude-from files, returning an exit code
+    /// and message on failure
+    fn compile_patterns(&mut self) -> Result<(), (i32, String)> {
+        let load = |files: &[String]| -> Result<Vec<Regex>, (i32, String)> {
+            let mut result = Vec::new();
+            for file in files {
+                let text = fs::read_to_string(file)
+                    .map_err(|e| (15, format!("Could not read pattern file {}: {}", file, e)))?;
+                result.extend(
+                    patterns::compile(&text, self.pattern_regex)
+                        .map_err(|e| (9, format!("{} in {}", e, file)))?,
+                );
+            }
+            Ok(result)
+        };
+        self.includes = load(&self.include_from)?;
+        self.excludes = load(&self.exclude_from)?;
+        debug!("Include patterns {:?}, exclude patterns {:?}", self.includes, self.excludes);
+        Ok(())
+    }
+
+    fn add_verbosity(&mut self, by: usize) {
+        self.verbosity = self.verbosity.saturating_add(by.min(u8::MAX as usize) as u8);
+        self.verbose = self.verbosity > 0;
+    }
+
+    /// The log level implied by the number of -v flags, if any were passed
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        match self.verbosity {
+            0 => None,
+            1 => Some(LevelFilter::Warn),
+            2 => Some(LevelFilter::Info),
+            3 => Some(LevelFilter::Debug),
+            _ => Some(LevelFilter::Trace),
+        }
+    }
+
+    /// Whether there is a command to run - with --json or --listen and no command, or
+    /// with --no-run, there is not
+    #[inline]
+    pub fn has_command(&self) -> bool {
+        !self.no_run && (!self.command.is_empty() || self.command_template.is_some())
+    }
+
+    /// The command to run on changes, for --confirm to show before running it - the words of
+    /// the command as passed, and any --on-* commands
+    pub fn describe_command(&self) -> String {
+        let mut result = match &self.command_template {
+            Some(_) => format!("the {} command", COMMAND_TEMPLATE_LONG),
+            None => self.command.join(" "),
+        };
+        for (long, line) in [
+            (ON_CREATE_LONG, &self.on_create),
+            (ON_MODIFY_LONG, &self.on_modify),
+            (ON_DELETE_LONG, &self.on_delete),
+        ] {
+            if let Some(line) = line {
+                if !result.is_empty() {
+                    result.push_str(", ");
+                }
+                result.push_str(&format!("{} '{}'", long, line));
+            }
+        }
+        result
+    }
+
+    /// A path to pass to the command, with the separators --slash asks for
+    pub fn slashed(&self, path: String) -> String {
+        match self.slash {
+            // On unix a backslash is part of a file name, never a separator
+            Some('/') if cfg!(windows) => path.replace('\\', "/"),
+            Some('\\') => path.replace('/', "\\"),
+            _ => path,
+        }
+    }
+
+    /// Whether any of --on-create, --on-modify or --on-delete was passed
+    fn has_change_commands(&self) -> bool {
+        self.on_create.is_some() || self.on_modify.is_some() || self.on_delete.is_some()
+    }
+
+    /// Runs the command for a batch of changes, each path paired with what happened to it -
+    /// paths go to the --on-* command for their kind of change where there is one, and to the
+    /// command otherwise; returns true if every command run succeeded
+    pub fn run_for_changes(&self, paths: &[String], changes: &[Change]) -> bool {
+        if self.no_run {
+            return true;
+        }
+        if !self.has_change_commands() {
+            return !self.has_command() || self.run_command(&paths.to_vec());
+        }
+        let mut rest = Vec::new();
+        let mut succeeded = true;
+        for (kind, line) in [
+            (Change::Delete, &self.on_delete),
+            (Change::Create, &self.on_create),
+            (Change::Modify, &self.on_modify),
+        ] {
+            let group: Vec<String> = paths
+                .iter()
+                .zip(changes)
+                .filter(|(_, change)| **change == kind)
+                .map(|(path, _)| path.clone())
+                .collect();
+            if group.is_empty() {
+                continue;
+            }
+            match line {
+                Some(line) => {
+                    info!("{} path(s) {:?} - running {}", group.len(), kind, line);
+                    let mut args = self.clone();
+                    args.command = vec![line.clone()];
+                    args.command_template = None;
+                    args.shell = true;
+                    args.raw_shell = true;
+                    succeeded &= args.run_command(&group);
+                }
+                None => rest.extend(group),
+            }
+        }
+        if !rest.is_empty() && self.has_command() {
+            succeeded &= self.run_command(&rest);
+        }
+        succeeded
+    }
+
+    /// These arguments, for watching the same directory after it was renamed to `dir` - the
+    /// files compared against changed paths beneath it are renamed with it
+    pub fn moved_to(&self, dir: &Path) -> Args {
+        let mut result = self.clone();
+        result.respell(&self.dir(), dir);
+        result.path = dir.to_string_lossy().to_string();
+        result.given_path = Some(result.path.clone());
+        result.literal_dir = dir.to_path_buf();
+        result.canonical_dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        if result.rust {
+            result.apply_rust_preset();
+        }
+        result
+    }
+
+    /// Whether watching for `other` needs different watchers than for these arguments
+    pub fn watches_differently(&self, other: &Args) -> bool {
+        self.watch_roots() != other.watch_roots()
+            || self.poll_paths != other.poll_paths
+            || self.resolve_symlink_targets != other.resolve_symlink_targets
+    }
+
+    /// The directories to watch, and how - with --watch-list, the directories containing
+    /// the listed files, otherwise just the target directory - then any --also-watch
+    /// directories, and the directories containing --also-watch files
+    pub fn watch_roots(&self) -> Vec<(PathBuf, RecursiveMode)> {
+        let mut result: Vec<(PathBuf, RecursiveMode)> = Vec::new();
+        if self.watch_list.is_empty() {
+            result.push((self.dir(), self.recursion_mode()));
+        }
+        for file in &self.watch_list {
+            if let Some(parent) = file.parent() {
+                if !result.iter().any(|(dir, _)| dir == parent) {
+                    result.push((parent.to_path_buf(), RecursiveMode::NonRecursive));
+                }
+            }
+        }
+        for (path, is_dir) in &self.also_watch {
+            // A file's directory, so replacing the file (as many editors save) is noticed
+            let (dir, mode) = match (is_dir, path.parent()) {
+                (true, _) | (false, None) => (path.as_path(), self.recursion_mode()),
+                (false, Some(parent)) => (parent, RecursiveMode::NonRecursive),
+            };
+            if !result.iter().any(|(root, _)| root == dir) {
+                result.push((dir.to_path_buf(), mode));
+            }
+        }
+        result
+    }
+
+    /// The directories -r makes changed paths relative to - the watched directory, spelled
+    /// both as given and canonically, and any --also-watch directories; a path is made relative
+    /// to the deepest one containing it
+    pub fn relative_roots(&self) -> Vec<PathBuf> {
+        let mut result = vec![self.dir()];
+        // Some events spell paths through the other spelling from the one watched - when the
+        // directory is a symlink, say, or beneath one
+        for dir in [&self.literal_dir, &self.canonical_dir] {
+            if !dir.as_os_str().is_empty() && !result.contains(dir) {
+                result.push(dir.clone());
+            }
+        }
+        for (path, is_dir) in &self.also_watch {
+            if *is_dir && !result.contains(path) {
+                result.push(path.clone());
+            }
+        }
+        result
+    }
+
+    /// For a path outside the watched directory, whether --also-watch makes it a change: a
+    /// file it names always is, and a path beneath a directory it names is if the filters
+    /// accept it, but nothing else in the directory of a named file is; `None` for paths
+    /// --also-watch has nothing to say about
+    fn also_watched(&self, path: &Path) -> Option<Result<String, String>> {
+        if self.also_watch.is_empty() || path.starts_with(self.dir()) {
+            return None;
+        }
+        for (watched, is_dir) in &self.also_watch {
+            if *is_dir && path.starts_with(watched) {
+                return Some(self.filter_verdict(path));
+            } else if !*is_dir && path == watched {
+                return Some(Ok(String::from("a named file always counts")));
+            }
         }
+        self.also_watch
+            .iter()
+            .any(|(watched, is_dir)| !is_dir && watched.parent() == path.parent())
+            .then(|| Err(format!("beside an {} file, but not the file itself", ALSO_WATCH_LONG)))
     }
 
     #[inline]
@@ -143,7 +1197,15 @@ impl Args {
             if !result.is_empty() {
                 result.push(' ');
             }
-            result.push_str(maybe_quote_or_escape(st).as_str());
+            if self.raw_shell {
+                result.push_str(st);
+            } else {
+                result.push_str(maybe_quote_or_escape(st).as_str());
+            }
+        }
+        if self.pass_dir {
+            result.push(' ');
+            result.push_str(maybe_quote_or_escape(&self.slashed(self.path.clone())).as_str());
         }
         if self.pass_changed_paths {
             for p in addtl {
@@ -156,21 +1218,11 @@ impl Args {
         result
     }
 
-    pub fn run_command(&self, additional_args: &Vec<String>) {
+    fn build_command(&self, additional_args: &Vec<String>) -> Command {
         let mut cmd: Command = if self.shell {
             // If a shell command, we need to concatenate all of the arguments into a single string
             // and ensure they are escaped
-            if cfg!(target_os = "windows") {
-                let mut result = Command::new("cmd");
-                result.arg("/C");
-                result.arg(self.args_as_string(additional_args));
-                result
-            } else {
-                let mut result = Command::new("sh");
-                result.arg("-c");
-                result.arg(self.args_as_string(additional_args));
-                result
-            }
+            shell_command(self.args_as_string(additional_args))
         } else {
             let mut result = Command::new(self.command.first().expect("Command is empty"));
             for c in self.command.iter().skip(1) {
@@ -178,165 +1230,1323 @@ impl Args {
             }
             result
         };
+        if self.pass_dir && !self.shell {
+            cmd.arg(self.slashed(self.path.clone()));
+        }
         if self.pass_changed_paths && !self.shell {
             // if self.shell, we already appended them above
             for path in additional_args {
                 cmd.arg(path);
             }
         }
+        cmd
+    }
+
+    /// Runs the command, waiting for it to exit, and returns true if it succeeded
+    pub fn run_command(&self, additional_args: &Vec<String>) -> bool {
+        if self.scratch_dir && self.scratch.is_none() {
+            return self.run_in_scratch_dir(additional_args);
+        }
+        let run = Run::start(additional_args.len());
+        if !self.paths_as_response_file || !self.pass_changed_paths || additional_args.is_empty()
+        {
+            return self.run_with(additional_args, &run);
+        }
+        // Pass a single argument naming a file listing the paths, one per line
+        let file = response_file_path();
+        let mut text = additional_args.join("\n");
+        text.push('\n');
+        if let Err(e) = fs::write(&file, text) {
+            status_eprintln!("Could not write response file {}: {}", file.display(), e);
+            return self.on_spawn_error(&run, e);
+        }
+        cleanup::register(&file);
+        let arg = format!("{}{}", self.response_file_prefix, file.display());
+        let result = self.run_with(&vec![arg], &run);
+        if let Err(e) = fs::remove_file(&file) {
+            debug!("Could not remove response file {:?}: {}", file, e);
+        }
+        cleanup::unregister(&file);
+        result
+    }
+
+    /// With --scratch-dir, create the run's directory and run the command with it; when the
+    /// command is not waited for here, whatever waits for it removes the directory
+    fn run_in_scratch_dir(&self, additional_args: &Vec<String>) -> bool {
+        let dir = scratch_dir_path();
+        if let Err(e) = fs::create_dir(&dir) {
+            status_eprintln!("Could not create scratch directory {}: {}", dir.display(), e);
+            return self.on_spawn_error(&Run::start(additional_args.len()), e);
+        }
+        cleanup::register(&dir);
+        let mut args = self.clone();
+        let spelled = dir.to_string_lossy();
+        args.command = args.command.iter().map(|c| c.replace(SCRATCH_TOKEN, &spelled)).collect();
+        args.env.push((SCRATCH_ENV.to_string(), spelled.to_string()));
+        args.scratch = Some(dir);
+        let result = args.run_command(additional_args);
+        if !(args.detach || args.restart) || args.pty {
+            args.remove_scratch();
+        }
+        result
+    }
+
+    /// With --scratch-dir, remove the run's directory once the command has exited
+    fn remove_scratch(&self) {
+        if let Some(dir) = &self.scratch {
+            if let Err(e) = fs::remove_dir_all(dir) {
+                debug!("Could not remove scratch directory {:?}: {}", dir, e);
+            }
+            cleanup::unregister(dir);
+        }
+    }
+
+    fn run_with(&self, additional_args: &Vec<String>, run: &Run) -> bool {
+        let mut cmd = if let Some(text) = &self.command_template {
+            match template::render(text, additional_args, self.path.as_str()) {
+                Ok(rendered) => shell_command(rendered),
+                Err(e) => {
+                    status_eprintln!("Could not render command template: {}", e);
+                    return self.on_spawn_error(run, e);
+                }
+            }
+        } else {
+            self.build_command(additional_args)
+        };
+        if self.clean_env {
+            cmd.env_clear();
+        }
+        cmd.envs(self.env.iter().map(|(name, value)| (name, value)));
+        if let Some(mask) = self.umask {
+            set_umask(&mut cmd, mask);
+        }
         info!("Launch {:?}", cmd);
+        if self.pty {
+            return match pty::run(&cmd, self.kill_children_on_exit, self.clean_env) {
+                Ok(status) => {
+                    let code = i32::try_from(status.exit_code()).ok();
+                    self.on_exit(&cmd, run, code, status.exit_code())
+                }
+                Err(e) => self.on_spawn_error(run, e),
+            };
+        }
+        if self.kill_children_on_exit || self.restart {
+            // So the child and anything it starts can be terminated together
+            own_process_group(&mut cmd);
+        }
+        if self.relays_output() {
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        if let Some(file) = &self.stdin_file {
+            // Opened for each run, so the command always reads it from the start
+            match fs::File::open(file) {
+                Ok(f) => {
+                    cmd.stdin(f);
+                }
+                Err(e) => {
+                    status_eprintln!("Could not open {} for the command's stdin: {}", file, e);
+                    return self.on_spawn_error(run, e);
+                }
+            }
+        }
+        if self.restart {
+            return self.spawn_restarting(cmd, run);
+        }
+        if self.detach {
+            return self.spawn_detached(cmd, run);
+        }
         // Launch the process
         let mut result = cmd.spawn();
         match result.as_mut() {
             Ok(ch) => {
+                if self.kill_children_on_exit {
+                    cleanup::register_child(ch.id());
+                }
+                self.child_started(ch.id());
+                let relays = self.relay_output(ch);
                 trace!("Enter wait for {:?}", ch);
                 // Wait for the process to exit.  Since we have a single timer thread, this
                 // also guarantees we can't be running two copies of the command concurrently
-                match ch.wait() {
-                    Ok(status) => {
-                        // Abort on error if necessary
-                        if self.exit_on_error && !status.success() {
-                            eprintln!(
-                                "Process exited with {} and exit-on-error is set.  Exiting.",
-                                status
-                            );
-                            std::process::exit(12);
-                        }
-                        if self.verbose {
-                            eprintln!("Command success: {:?}", cmd);
-                        }
-                        if self.once && status.success() {
-                            info!("--once was passed and command has succeeded.  Exiting.");
-                            std::process::exit(0);
-                        }
-                    }
+                let waited = ch.wait();
+                if self.kill_children_on_exit {
+                    cleanup::unregister_child(ch.id());
+                }
+                self.child_exited(ch.id());
+                streams::drain(relays);
+                match waited {
+                    Ok(status) => self.on_exit(&cmd, run, status.code(), status),
                     Err(e) => {
                         if self.verbose {
-                            eprintln!("{}", e);
+                            status_eprintln!("{}", e);
                         }
                         error!("Cmd error: {:?}", e);
+                        run.finish(None, false);
                         if self.exit_on_error {
                             error!("Error launching process. Exiting.");
-                            std::process::exit(100);
+                            cleanup::exit(100);
                         }
+                        false
                     }
                 }
             }
-            Err(e) => {
-                if self.verbose {
-                    eprintln!("{}", e);
+            Err(e) => self.on_spawn_error(run, e),
+        }
+    }
+
+    /// With --detach, start the command and return without waiting for it, once fewer than
+    /// --max-detached copies are running; a thread waits for it to exit, applying -x and -o
+    /// when it does
+    fn spawn_detached(&self, mut cmd: Command, run: &Run) -> bool {
+        let (count, exited) = &self.runs.detached;
+        let mut running = count.lock().unwrap();
+        if let Some(max) = self.max_detached {
+            while *running >= max {
+                debug!("{} detached commands running - wait for one to exit", running);
+                running = exited.wait(running).unwrap();
+            }
+        }
+        let mut ch = match cmd.spawn() {
+            Ok(ch) => ch,
+            Err(e) => return self.on_spawn_error(run, e),
+        };
+        *running += 1;
+        drop(running);
+        if self.kill_children_on_exit {
+            cleanup::register_child(ch.id());
+        }
+        self.child_started(ch.id());
+        let relays = self.relay_output(&mut ch);
+        let (args, run) = (self.clone(), *run);
+        std::thread::spawn(move || {
+            let waited = ch.wait();
+            if args.kill_children_on_exit {
+                cleanup::unregister_child(ch.id());
+            }
+            args.child_exited(ch.id());
+            args.remove_scratch();
+            streams::drain(relays);
+            let (count, exited) = &args.runs.detached;
+            *count.lock().unwrap() -= 1;
+            exited.notify_all();
+            match waited {
+                Ok(status) => {
+                    args.on_exit(&cmd, &run, status.code(), status);
+                }
+                Err(e) => {
+                    error!("Error waiting for detached command: {}", e);
+                    run.finish(None, false);
+                }
+            }
+        });
+        true
+    }
+
+    /// With --restart, stop the command if it is still running from the last batch, then start
+    /// it and return without waiting for it; a thread waits for it to exit, applying -x and -o
+    /// if it exits by itself
+    fn spawn_restarting(&self, mut cmd: Command, run: &Run) -> bool {
+        let mut running = self.stop_restarting(self.runs.restarting.0.lock().unwrap());
+        let mut ch = match cmd.spawn() {
+            Ok(ch) => ch,
+            Err(e) => return self.on_spawn_error(run, e),
+        };
+        let pid = ch.id();
+        *running = Some((pid, false));
+        // Under the lock, so a quick succession of restarts writes the file in order
+        self.child_started(pid);
+        drop(running);
+        // In its own process group, it no longer gets a Ctrl-C meant for watchfs, so it is
+        // always stopped when watchfs exits
+        cleanup::register_child(pid);
+        let relays = self.relay_output(&mut ch);
+        let (args, run) = (self.clone(), *run);
+        std::thread::spawn(move || {
+            let waited = ch.wait();
+            cleanup::unregister_child(pid);
+            args.child_exited(pid);
+            args.remove_scratch();
+            streams::drain(relays);
+            let (current, exited) = &args.runs.restarting;
+            let stopped = current.lock().unwrap().take().map(|(_, stopping)| stopping);
+            exited.notify_all();
+            match waited {
+                Ok(status) if stopped == Some(true) => {
+                    debug!("Stopped {} for restart", pid);
+                    run.finish(status.code(), args.is_success(status.code()));
+                }
+                Ok(status) => {
+                    args.on_exit(&cmd, &run, status.code(), status);
                 }
-                error!("Error launching process: {}", e);
-                if self.exit_on_error {
-                    std::process::exit(101);
+                Err(e) => {
+                    error!("Error waiting for command: {}", e);
+                    run.finish(None, false);
                 }
             }
+        });
+        true
+    }
+
+    /// With --restart, stop the command if it is running, waiting for it to exit
+    fn stop_restarting<'a>(
+        &self,
+        mut running: MutexGuard<'a, Option<(u32, bool)>>,
+    ) -> MutexGuard<'a, Option<(u32, bool)>> {
+        let (_, exited) = &self.runs.restarting;
+        if let Some((pid, _)) = *running {
+            info!("Restart - stop {} with signal {}", pid, self.stop_signal);
+            *running = Some((pid, true));
+            cleanup::signal(pid, self.stop_signal);
+            let timeout = self.stop_timeout.to_std().unwrap_or_default();
+            let (guard, waited) = exited
+                .wait_timeout_while(running, timeout, |r| r.is_some())
+                .unwrap();
+            running = guard;
+            if waited.timed_out() {
+                status_eprintln!(
+                    "Command did not exit within {}ms of being signalled - killing it",
+                    self.stop_timeout.num_milliseconds()
+                );
+                cleanup::signal(pid, cleanup::KILL);
+                running = exited.wait_while(running, |r| r.is_some()).unwrap();
+            }
         }
+        running
     }
 
-    pub fn new() -> Args {
-        // Fill in defaults:
-        let mut result = Args::default();
-        let args: Vec<String> = std::env::args().collect();
-        // First argument is the path to this program, so start from 1
-        let mut i = 1_usize;
+    /// Before exiting with --exit-on, wait for --detach commands to exit, and stop a
+    /// --restart one - which may be a server that would otherwise never exit
+    pub fn finish_running(&self) {
+        if self.restart {
+            drop(self.stop_restarting(self.runs.restarting.0.lock().unwrap()));
+        } else if self.detach {
+            let (count, exited) = &self.runs.detached;
+            let running = count.lock().unwrap();
+            debug!("Wait for {} detached commands to exit", running);
+            drop(exited.wait_while(running, |running| *running > 0).unwrap());
+        }
+    }
 
-        // Update args with command-line flags
-        while i < args.len() {
-            if let Some(arg) = args.get(i) {
-                trace!("Arg: {}", arg);
-                match arg.as_str() {
-                    // Simple arguments
-                    VERBOSE_SHORT | VERBOSE_LONG => result.verbose = true,
-                    HELP_SHORT | HELP_LONG => result.help = true,
-                    RELATIVIZE_SHORT | RELATIVIZE_LONG => result.relativize_paths = true,
-                    ONCE_SHORT | ONCE_LONG => result.once = true,
-                    PASS_CHANGED_PATHS_SHORT | PASS_CHANGED_PATHS_LONG => {
-                        result.pass_changed_paths = true
-                    }
-                    SHELL_SHORT | SHELL_LONG => result.shell = true,
-                    NON_RECURSIVE_SHORT | NON_RECURSIVE_LONG => result.non_recursive = true,
-                    EXIT_ON_ERROR_SHORT | EXIT_ON_ERROR_LONG => result.exit_on_error = true,
-                    FILTER_SHORT | FILTER_LONG => {
+    /// With --warm-up, run the warm-up command until it succeeds, exiting if it has not
+    /// after the allowed number of attempts
+    pub fn warm_up(&self) {
+        let Some(line) = &self.warm_up else {
+            return;
+        };
+        let interval = self.warm_up_interval.to_std().unwrap_or_default();
+        for attempt in 1..=self.warm_up_attempts {
+            info!("Warm-up attempt {}: {}", attempt, line);
+            match shell_command(line.clone()).status() {
+                Ok(status) if status.success() => {
+                    debug!("Warm-up succeeded after {} attempts", attempt);
+                    return;
+                }
+                Ok(status) => debug!("Warm-up attempt {} exited with {}", attempt, status),
+                Err(e) => status_eprintln!("Could not run warm-up command: {}", e),
+            }
+            if attempt < self.warm_up_attempts {
+                std::thread::sleep(interval);
+            }
+        }
+        status_eprintln!(
+            "Warm-up command did not succeed after {} attempts - exiting",
+            self.warm_up_attempts
+        );
+        cleanup::exit(20);
+    }
+
+    /// Whether the command exiting with `code` counts as success - `None` if it was ended by
+    /// a signal, which never does
+    fn is_success(&self, code: Option<i32>) -> bool {
+        match code {
+            Some(code) if self.success_codes.is_empty() => code == 0,
+            Some(code) => self.success_codes.contains(&code),
+            None => false,
+        }
+    }
+
+    /// Whether the command's output is relayed through watchfs rather than inherited
+    fn relays_output(&self) -> bool {
+        self.tag_streams || self.timestamp_output || self.max_output_bytes.is_some()
+    }
+
+    /// Start relaying the command's output, if piped for --tag-streams, --timestamp-output or
+    /// --max-output-bytes
+    fn relay_output(&self, child: &mut Child) -> Vec<JoinHandle<()>> {
+        let timestamps = self.timestamp_output.then(|| {
+            self.timestamp_format
+                .as_deref()
+                .unwrap_or(DEFAULT_TIMESTAMP_FORMAT)
+        });
+        streams::relay(child, self.tag_streams, timestamps, self.max_output_bytes)
+    }
+
+    /// With --child-pidfile, record that the command started as process `pid`
+    fn child_started(&self, pid: u32) {
+        if let Some(file) = &self.child_pidfile {
+            pidfile::write(file, pid);
+        }
+    }
+
+    /// With --child-pidfile, record that process `pid` exited
+    fn child_exited(&self, pid: u32) {
+        if let Some(file) = &self.child_pidfile {
+            pidfile::exited(file, pid);
+        }
+    }
+
+    /// Handles the command exiting, and returns whether it succeeded
+    fn on_exit<S: Display>(&self, cmd: &Command, run: &Run, code: Option<i32>, status: S) -> bool {
+        let success = self.is_success(code);
+        run.finish(code, success);
+        self.touch_marker(success);
+        self.ring_bell(success);
+        self.count_failures(success);
+        // Abort on error if necessary
+        if self.exit_on_error && !success {
+            status_eprintln!(
+                "Process exited with {} and exit-on-error is set.  Exiting.",
+                status
+            );
+            cleanup::exit(12);
+        }
+        if self.verbose {
+            status_eprintln!("Command success: {:?}", cmd);
+        }
+        if self.once && success {
+            info!("--once was passed and command has succeeded.  Exiting.");
+            cleanup::exit(0);
+        }
+        success
+    }
+
+    /// With --max-failures, track the run of consecutive failures, exiting once it is long
+    /// enough
+    fn count_failures(&self, success: bool) {
+        let Some(max) = self.max_failures else {
+            return;
+        };
+        if success {
+            self.runs.failures.store(0, Ordering::SeqCst);
+            return;
+        }
+        let streak = self.runs.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if streak >= max {
+            status_eprintln!(
+                "The command failed {} times in a row, reaching {} {}.  Exiting.",
+                streak,
+                MAX_FAILURES_LONG,
+                max
+            );
+            cleanup::exit(23);
+        }
+        debug!("{} consecutive failures", streak);
+    }
+
+    /// Create or update the modification time of the --touch-on-success or
+    /// --touch-on-failure file, if there is one for the outcome of a run
+    fn touch_marker(&self, success: bool) {
+        let marker = if success {
+            &self.touch_on_success
+        } else {
+            &self.touch_on_failure
+        };
+        if let Some(path) = marker {
+            let touched = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|file| file.set_modified(std::time::SystemTime::now()));
+            if let Err(e) = touched {
+                status_eprintln!("Could not touch {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// With --bell-on-error or --bell-on-success, write a BEL to stderr if it is a terminal
+    fn ring_bell(&self, success: bool) {
+        let wanted = if success {
+            self.bell_on_success
+        } else {
+            self.bell_on_error
+        };
+        let mut stderr = std::io::stderr();
+        if wanted && stderr.is_terminal() {
+            let _ = stderr.write_all(b"\x07").and_then(|_| stderr.flush());
+        }
+    }
+
+    fn on_spawn_error<E: Display>(&self, run: &Run, e: E) -> bool {
+        run.finish(None, false);
+        self.remove_scratch();
+        if self.verbose {
+            status_eprintln!("{}", e);
+        }
+        error!("Error launching process: {}", e);
+        self.touch_marker(false);
+        self.ring_bell(false);
+        self.count_failures(false);
+        if self.exit_on_error {
+            cleanup::exit(101);
+        }
+        false
+    }
+
+    /// The arguments to watch with - one set for each `[[rule]]` of the configuration file if
+    /// it has any, otherwise just the one
+    pub fn new() -> Vec<Args> {
+        let cli: Vec<String> = std::env::args().collect();
+        // A first pass finds any --config; since the command-line overrides the file, it is
+        // parsed again after the file's arguments when there is one
+        let mut result = or_exit(Args::parse(&cli));
+        if result.init {
+            // Before reading any existing file, which may be what is being replaced
+            match config::init(result.force) {
+                Ok(path) => {
+                    status_println!("Wrote {}", path.display());
+                    std::process::exit(0);
+                }
+                Err(e) => print_help_and_exit(21, Some(e)),
+            }
+        }
+        if let Some(file) = result.config.clone().map(PathBuf::from).or_else(config::discover) {
+            let text = or_exit(read_file(&file.to_string_lossy(), CONFIG_LONG));
+            let invalid = |e: String| -> ! {
+                print_help_and_exit(
+                    18,
+                    Some(format!("Invalid config file {}: {}", file.display(), e)),
+                )
+            };
+            let rules = match config::parse(&text, &file) {
+                Ok(cfg) => cfg.rules.len(),
+                Err(e) => invalid(e),
+            };
+            if rules > 0 {
+                return (0..rules)
+                    .map(|ix| match Args::with_config(&cli, &text, &file, Some(ix)) {
+                        Ok(rule) => rule.finish(),
+                        Err((18, e)) => invalid(e),
+                        Err((code, e)) => print_help_and_exit(code, Some(e)),
+                    })
+                    .collect();
+            }
+            result = match Args::with_config(&cli, &text, &file, None) {
+                Ok(result) => result,
+                Err((18, e)) => invalid(e),
+                Err((code, e)) => print_help_and_exit(code, Some(e)),
+            };
+        }
+        vec![result.finish()]
+    }
+
+    /// Parse the command-line `cli` after the arguments from the text of configuration file
+    /// `file` - and those of its `[[rule]]` number `rule`, if set - so the command-line
+    /// overrides the rule, which overrides the rest of the file; a file which is not valid
+    /// configuration fails with code 18
+    fn with_config(
+        cli: &[String],
+        text: &str,
+        file: &Path,
+        rule: Option<usize>,
+    ) -> Result<Args, (i32, String)> {
+        let mut cfg = config::parse(text, file).map_err(|e| (18, e))?;
+        let mut args = vec![cli[0].clone()];
+        args.extend(cfg.flags);
+        if let Some(ix) = rule {
+            if ix >= cfg.rules.len() {
+                return Err((18, format!("it no longer has a [[rule]] {}", ix + 1)));
+            }
+            let rule = cfg.rules.swap_remove(ix);
+            args.extend(rule.flags);
+            if !rule.command.is_empty() {
+                cfg.command = rule.command;
+            }
+        }
+        args.extend(cli.iter().skip(1).cloned());
+        let mut result = Args::parse(&args)?;
+        result.rule = rule;
+        if result.command.is_empty() {
+            result.command = cfg.command;
+        }
+        // Canonical, to recognize changes to it with --config-reload
+        result.config_file = Some(fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf()));
+        Ok(result)
+    }
+
+    /// With --config-reload, these arguments with the delay, filters and command replaced by
+    /// those resulting from `text`, the new contents of the configuration file - and with
+    /// --graceful-restart-on-config-change, what is watched; other settings only take effect
+    /// on restart
+    pub fn reload(&self, text: &str) -> Result<Args, String> {
+        let file = self.config_file.as_ref().ok_or("No configuration file was read")?;
+        let cli: Vec<String> = std::env::args().collect();
+        let mut fresh = Args::with_config(&cli, text, file, self.rule)
+            .map_err(|(_, message)| message)?;
+        if self.restart_on_config_change {
+            fresh.locate_dir().map_err(|(_, message)| message)?;
+        }
+        if fresh.rust {
+            fresh.apply_rust_preset();
+        }
+        fresh.compile_patterns().map_err(|(_, message)| message)?;
+        let mut result = self.clone();
+        if self.restart_on_config_change {
+            result.path = fresh.path;
+            result.given_path = fresh.given_path;
+            result.literal_dir = fresh.literal_dir;
+            result.canonical_dir = fresh.canonical_dir;
+            result.non_recursive = fresh.non_recursive;
+            result.watch_list = fresh.watch_list;
+            result.also_watch = fresh.also_watch;
+            result.poll_paths = fresh.poll_paths;
+            result.resolve_symlink_targets = fresh.resolve_symlink_targets;
+            result.only_under = fresh.only_under;
+            result.excluded = fresh.excluded;
+        }
+        result.delay_seconds = fresh.delay_seconds;
+        result.debounce_for = fresh.debounce_for;
+        result.filter = fresh.filter;
+        result.includes = fresh.includes;
+        result.excludes = fresh.excludes;
+        // Removing the command leaves the one being run, rather than falling back to `echo`
+        if !fresh.command.is_empty() {
+            result.command = if self.expand_env && !self.shell {
+                fresh
+                    .command
+                    .iter()
+                    .map(|c| checked_expand_env(c).map_err(|(_, message)| message))
+                    .collect::<Result<_, _>>()?
+            } else {
+                fresh.command
+            };
+        }
+        Ok(result)
+    }
+
+    /// Parse flags and the command from `args`, whose first element is the program
+    fn parse(args: &[String]) -> Result<Args, (i32, String)> {
+        // Fill in defaults:
+        let mut result = Args::default();
+        if let Ok(secs) = std::env::var(DELAY_ENV) {
+            result.delay_seconds = parse_seconds(&secs, DELAY_ENV)?;
+        }
+        // First argument is the path to this program, so start from 1
+        let mut i = 1_usize;
+
+        // Update args with command-line flags
+        while i < args.len() {
+            if let Some(arg) = args.get(i) {
+                trace!("Arg: {}", arg);
+                match arg.as_str() {
+                    // Simple arguments
+                    VERBOSE_SHORT | VERBOSE_LONG => result.add_verbosity(1),
+                    stacked
+                        if stacked.len() > 2
+                            && stacked
+                                .strip_prefix('-')
+                                .is_some_and(|v| v.bytes().all(|b| b == b'v')) =>
+                    {
+                        // -vv, -vvv...
+                        result.add_verbosity(stacked.len() - 1)
+                    }
+                    level if level.starts_with(VERBOSE_LEVEL_PREFIX) => {
+                        let n = &level[VERBOSE_LEVEL_PREFIX.len()..];
+                        match n.parse::<usize>() {
+                            Ok(n) => result.add_verbosity(n),
+                            Err(_) => {
+                                return Err((
+                                    14,
+                                    format!("{} must be a number, not '{}'", VERBOSE_LONG, n),
+                                ))
+                            }
+                        }
+                    }
+                    HELP_SHORT | HELP_LONG => result.help = true,
+                    HELP_JSON_LONG => result.help_json = true,
+                    COMPLETIONS_LONG => {
+                        let shell = value_of(args, &mut i, None, COMPLETIONS_LONG, "a shell name")?;
+                        match completions::script_for(shell) {
+                            Some(script) => {
+                                print!("{}", script);
+                                std::process::exit(0);
+                            }
+                            None => {
+                                return Err((
+                                    14,
+                                    format!(
+                                        "Unsupported shell '{}' for {} - must be one of {}",
+                                        shell,
+                                        COMPLETIONS_LONG,
+                                        completions::SHELLS.join(", ")
+                                    ),
+                                ))
+                            }
+                        }
+                    }
+                    RELATIVIZE_SHORT | RELATIVIZE_LONG => result.relativize_paths = true,
+                    SLASH_LONG => {
+                        let style = value_of(
+                            args,
+                            &mut i,
+                            None,
+                            SLASH_LONG,
+                            "forward, backward or native",
+                        )?;
+                        result.slash = match style.as_str() {
+                            "forward" => Some('/'),
+                            "backward" => Some('\\'),
+                            "native" => None,
+                            _ => {
+                                return Err((
+                                    14,
+                                    format!(
+                                        "{} must be forward, backward or native, not '{}'",
+                                        SLASH_LONG, style
+                                    ),
+                                ))
+                            }
+                        };
+                    }
+                    ONCE_SHORT | ONCE_LONG => result.once = true,
+                    PASS_CHANGED_PATHS_SHORT | PASS_CHANGED_PATHS_LONG => {
+                        result.pass_changed_paths = true
+                    }
+                    PASS_DIR_LONG => result.pass_dir = true,
+                    SHELL_SHORT | SHELL_LONG => result.shell = true,
+                    RAW_SHELL_LONG => result.raw_shell = true,
+                    NON_RECURSIVE_SHORT | NON_RECURSIVE_LONG => result.non_recursive = true,
+                    ONE_FILESYSTEM_LONG => result.one_filesystem = true,
+                    OWNER_LONG => {
+                        let user = value_of(args, &mut i, None, OWNER_LONG, "a user id or name")?;
+                        if cfg!(not(unix)) {
+                            return Err((14, format!("{} is only supported on unix", OWNER_LONG)));
+                        }
+                        match user_id(user) {
+                            Some(uid) => result.owner = Some(uid),
+                            None => {
+                                return Err((
+                                    14,
+                                    format!("{}: no such user '{}'", OWNER_LONG, user),
+                                ))
+                            }
+                        }
+                    }
+                    PRUNE_DIR_GLOB_LONG => {
+                        for glob in list_value_of(args, &mut i, PRUNE_DIR_GLOB_LONG, "globs")? {
+                            match patterns::compile(&glob, false) {
+                                Ok(rexes) => result.prune_dirs.extend(rexes),
+                                Err(e) => {
+                                    return Err((14, format!("{}: {}", PRUNE_DIR_GLOB_LONG, e)))
+                                }
+                            }
+                        }
+                    }
+                    EXIT_ON_ERROR_SHORT | EXIT_ON_ERROR_LONG => result.exit_on_error = true,
+                    MAX_FAILURES_LONG => {
+                        result.max_failures = Some(count_value_of(args, &mut i, MAX_FAILURES_LONG)?)
+                    }
+                    SUCCESS_CODES_LONG => {
+                        for code in list_value_of(args, &mut i, SUCCESS_CODES_LONG, "exit codes")? {
+                            match code.trim().parse::<i32>() {
+                                Ok(code) => {
+                                    result.success_codes.insert(code);
+                                }
+                                Err(_) => {
+                                    return Err((
+                                        14,
+                                        format!(
+                                            "{} must be a list of exit codes, not '{}'",
+                                            SUCCESS_CODES_LONG, code
+                                        ),
+                                    ))
+                                }
+                            }
+                        }
+                    }
+                    PRESERVE_ORDER_LONG => result.preserve_order = true,
+                    JSON_LONG => result.json = true,
+                    FILTER_BASENAME_LONG => result.filter_basename = true,
+                    FILTER_COMPONENT_LONG => result.filter_component = true,
+                    INCLUDE_FROM_LONG => result.include_from.extend(list_value_of(
+                        args,
+                        &mut i,
+                        INCLUDE_FROM_LONG,
+                        "file paths",
+                    )?),
+                    EXCLUDE_FROM_LONG => result.exclude_from.extend(list_value_of(
+                        args,
+                        &mut i,
+                        EXCLUDE_FROM_LONG,
+                        "file paths",
+                    )?),
+                    PATTERN_SYNTAX_LONG => {
+                        let syntax =
+                            value_of(args, &mut i, None, PATTERN_SYNTAX_LONG, "glob or regex")?;
+                        result.pattern_regex = match syntax.as_str() {
+                            "glob" => false,
+                            "regex" => true,
+                            _ => {
+                                return Err((
+                                    14,
+                                    format!(
+                                        "{} must be glob or regex, not '{}'",
+                                        PATTERN_SYNTAX_LONG, syntax
+                                    ),
+                                ))
+                            }
+                        };
+                    }
+                    PATHS_AS_RESPONSE_FILE_LONG => result.paths_as_response_file = true,
+                    CONFIG_RELOAD_LONG => result.config_reload = true,
+                    GRACEFUL_RESTART_LONG => {
+                        result.config_reload = true;
+                        result.restart_on_config_change = true;
+                    }
+                    INIT_LONG => result.init = true,
+                    FORCE_LONG => result.force = true,
+                    BY_DIR_LONG => result.by_dir = true,
+                    NO_CANONICALIZE_LONG => result.no_canonicalize = true,
+                    CANONICAL_PATHS_LONG => result.canonical_paths = true,
+                    KEEP_MISSING_LONG => result.keep_missing = true,
+                    // Each overrides the other, so a configuration file's can be replaced
+                    EXISTING_ONLY_LONG => {
+                        result.existing_only = true;
+                        result.deletions_only = false;
+                    }
+                    DELETIONS_ONLY_LONG => {
+                        result.deletions_only = true;
+                        result.existing_only = false;
+                    }
+                    DETACH_LONG => result.detach = true,
+                    BELL_ON_ERROR_LONG => result.bell_on_error = true,
+                    BELL_ON_SUCCESS_LONG => result.bell_on_success = true,
+                    ON_CREATE_LONG => {
+                        let line = value_of(args, &mut i, None, ON_CREATE_LONG, "a shell command")?;
+                        result.on_create = Some(line.clone());
+                    }
+                    ON_MODIFY_LONG => {
+                        let line = value_of(args, &mut i, None, ON_MODIFY_LONG, "a shell command")?;
+                        result.on_modify = Some(line.clone());
+                    }
+                    ON_DELETE_LONG => {
+                        let line = value_of(args, &mut i, None, ON_DELETE_LONG, "a shell command")?;
+                        result.on_delete = Some(line.clone());
+                    }
+                    RESTART_LONG => result.restart = true,
+                    STOP_SIGNAL_LONG => {
+                        result.stop_signal = signal_value_of(args, &mut i, STOP_SIGNAL_LONG)?
+                    }
+                    STOP_TIMEOUT_LONG => {
+                        result.stop_timeout =
+                            duration_value_of(args, &mut i, None, STOP_TIMEOUT_LONG)?
+                    }
+                    MAX_DETACHED_LONG => {
+                        result.max_detached = Some(count_value_of(args, &mut i, MAX_DETACHED_LONG)?)
+                    }
+                    TOUCH_ON_SUCCESS_LONG => {
+                        let file =
+                            value_of(args, &mut i, None, TOUCH_ON_SUCCESS_LONG, "a file path")?;
+                        result.touch_on_success =
+                            Some(absolute_file_path(file, TOUCH_ON_SUCCESS_LONG)?);
+                    }
+                    TOUCH_ON_FAILURE_LONG => {
+                        let file =
+                            value_of(args, &mut i, None, TOUCH_ON_FAILURE_LONG, "a file path")?;
+                        result.touch_on_failure =
+                            Some(absolute_file_path(file, TOUCH_ON_FAILURE_LONG)?);
+                    }
+                    SHOW_TIMING_LONG => result.show_timing = true,
+                    ANNOUNCE_LONG => result.announce = true,
+                    SKIP_DUPLICATE_BATCHES_LONG => {
+                        result.skip_duplicate_batches = Some(duration_value_of(
+                            args,
+                            &mut i,
+                            None,
+                            SKIP_DUPLICATE_BATCHES_LONG,
+                        )?)
+                    }
+                    STABLE_FOR_LONG => {
+                        result.stable_for =
+                            Some(duration_value_of(args, &mut i, None, STABLE_FOR_LONG)?)
+                    }
+                    STABLE_POLL_LONG => {
+                        result.stable_poll =
+                            Some(duration_value_of(args, &mut i, None, STABLE_POLL_LONG)?)
+                    }
+                    AFTER_QUIET_ONLY_LONG => {
+                        result.after_quiet_only = Some(duration_value_of(
+                            args,
+                            &mut i,
+                            None,
+                            AFTER_QUIET_ONLY_LONG,
+                        )?)
+                    }
+                    RESPONSE_FILE_PREFIX_LONG => {
+                        let prefix =
+                            value_of(args, &mut i, None, RESPONSE_FILE_PREFIX_LONG, "a prefix")?;
+                        result.response_file_prefix = prefix.clone();
+                    }
+                    WARM_UP_LONG => {
+                        let line = value_of(args, &mut i, None, WARM_UP_LONG, "a shell command")?;
+                        result.warm_up = Some(line.clone());
+                    }
+                    WARM_UP_INTERVAL_LONG => {
+                        result.warm_up_interval =
+                            duration_value_of(args, &mut i, None, WARM_UP_INTERVAL_LONG)?
+                    }
+                    WARM_UP_ATTEMPTS_LONG => {
+                        result.warm_up_attempts =
+                            count_value_of(args, &mut i, WARM_UP_ATTEMPTS_LONG)?
+                    }
+                    RUN_LOG_LONG => {
+                        let file = value_of(args, &mut i, None, RUN_LOG_LONG, "a file")?;
+                        result.run_log = Some(file.clone());
+                    }
+                    STATUS_TO_LONG => {
+                        let to = value_of(args, &mut i, None, STATUS_TO_LONG, "a destination")?;
+                        result.status_to = Some(to.clone());
+                    }
+                    DEBOUNCE_FOR_LONG => {
+                        for item in list_value_of(args, &mut i, DEBOUNCE_FOR_LONG, "ext=duration")?
+                        {
+                            let parsed = item.split_once('=').and_then(|(ext, delay)| {
+                                let ext = ext.trim().trim_start_matches('.');
+                                match parse_duration(delay) {
+                                    Some(d) if d > Duration::zero() && !ext.is_empty() => {
+                                        Some((ext.to_string(), d))
+                                    }
+                                    _ => None,
+                                }
+                            });
+                            match parsed {
+                                Some(entry) => result.debounce_for.push(entry),
+                                None => return Err((
+                                    14,
+                                    format!(
+                                        "{} needs an extension and a delay > 0, e.g. css=200ms, not '{}'",
+                                        DEBOUNCE_FOR_LONG, item
+                                    ),
+                                )),
+                            }
+                        }
+                    }
+                    MAX_WATCHERS_LONG => {
+                        result.max_watchers = Some(count_value_of(args, &mut i, MAX_WATCHERS_LONG)?)
+                    }
+                    JSON_PRETTY_LONG => {
+                        result.json = true;
+                        result.json_pretty = true;
+                    }
+                    NO_RUN_LONG => result.no_run = true,
+                    KILL_CHILDREN_ON_EXIT_LONG => result.kill_children_on_exit = true,
+                    CHILD_PIDFILE_LONG => {
+                        let path = value_of(args, &mut i, None, CHILD_PIDFILE_LONG, "a file path")?;
+                        result.child_pidfile = Some(absolute_file_path(path, CHILD_PIDFILE_LONG)?);
+                    }
+                    CLEAN_ENV_LONG => result.clean_env = true,
+                    SCRATCH_DIR_LONG => result.scratch_dir = true,
+                    ENV_LONG => {
+                        let var = value_of(args, &mut i, None, ENV_LONG, "NAME=value")?;
+                        match var.split_once('=') {
+                            Some((name, value)) if !name.is_empty() => {
+                                result.env.push((name.to_string(), value.to_string()))
+                            }
+                            _ => {
+                                return Err((
+                                    14,
+                                    format!("{} must be NAME=value, not '{}'", ENV_LONG, var),
+                                ))
+                            }
+                        }
+                    }
+                    ENV_FILE_LONG => {
+                        let file = value_of(args, &mut i, None, ENV_FILE_LONG, "a file")?;
+                        result.env_files.push(file.clone());
+                    }
+                    UMASK_LONG => {
+                        let mask = value_of(args, &mut i, None, UMASK_LONG, "an octal umask")?;
+                        let digits = mask.strip_prefix("0o").unwrap_or(mask);
+                        match u32::from_str_radix(digits, 8) {
+                            Ok(umask) if umask <= 0o777 => result.umask = Some(umask),
+                            _ => {
+                                return Err((
+                                    14,
+                                    format!(
+                                        "{} must be an octal number from 000 to 777, not '{}'",
+                                        UMASK_LONG, mask
+                                    ),
+                                ))
+                            }
+                        }
+                    }
+                    RUST_LONG => result.rust = true,
+                    PRINT_WATCHED_PATHS_LONG => result.print_watched_paths = true,
+                    DEBUG_FILTER_LONG => result.debug_filter = true,
+                    TAG_STREAMS_LONG => result.tag_streams = true,
+                    TIMESTAMP_OUTPUT_LONG => result.timestamp_output = true,
+                    TIMESTAMP_FORMAT_LONG => {
+                        let format =
+                            value_of(args, &mut i, None, TIMESTAMP_FORMAT_LONG, "a format")?;
+                        if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                            return Err((
+                                14,
+                                format!(
+                                    "Invalid {} '{}' - use strftime fields such as %H:%M:%S",
+                                    TIMESTAMP_FORMAT_LONG, format
+                                ),
+                            ));
+                        }
+                        result.timestamp_format = Some(format.clone());
+                    }
+                    MAX_OUTPUT_BYTES_LONG => {
+                        result.max_output_bytes =
+                            Some(count_value_of(args, &mut i, MAX_OUTPUT_BYTES_LONG)?)
+                    }
+                    EMIT_EMPTY_LONG => result.emit_empty = true,
+                    RESOLVE_SYMLINK_TARGETS_LONG => result.resolve_symlink_targets = true,
+                    STDIN_FILE_LONG => {
+                        let file = value_of(args, &mut i, None, STDIN_FILE_LONG, "a file path")?;
+                        if let Err(e) = fs::File::open(file) {
+                            return Err((
+                                15,
+                                format!(
+                                    "Could not read {} file '{}': {}",
+                                    STDIN_FILE_LONG, file, e
+                                ),
+                            ));
+                        }
+                        result.stdin_file = Some(file.clone());
+                    }
+                    WATCH_LIST_LONG => {
+                        for file in list_value_of(args, &mut i, WATCH_LIST_LONG, "file paths")? {
+                            for line in read_file(&file, WATCH_LIST_LONG)?.lines() {
+                                let line = line.trim();
+                                if !line.is_empty() && !line.starts_with('#') {
+                                    result
+                                        .watch_list
+                                        .push(absolute_file_path(line, WATCH_LIST_LONG)?);
+                                }
+                            }
+                        }
+                    }
+                    ALSO_WATCH_LONG => {
+                        for path in list_value_of(args, &mut i, ALSO_WATCH_LONG, "paths")? {
+                            match fs::canonicalize(&path) {
+                                Ok(real) => {
+                                    let is_dir = real.is_dir();
+                                    result.also_watch.push((real, is_dir));
+                                }
+                                Err(e) => {
+                                    return Err((
+                                        14,
+                                        format!(
+                                            "{} {} cannot be found: {}",
+                                            ALSO_WATCH_LONG, path, e
+                                        ),
+                                    ))
+                                }
+                            }
+                        }
+                    }
+                    MAX_EVENTS_PER_SECOND_LONG => {
+                        result.max_events_per_second =
+                            Some(count_value_of(args, &mut i, MAX_EVENTS_PER_SECOND_LONG)?)
+                    }
+                    POLL_PATH_LONG => {
+                        for dir in list_value_of(args, &mut i, POLL_PATH_LONG, "directories")? {
+                            match fs::canonicalize(&dir) {
+                                Ok(path) if path.is_dir() => result.poll_paths.push(path),
+                                Ok(_) => {
+                                    return Err((
+                                        14,
+                                        format!("{} {} is not a directory", POLL_PATH_LONG, dir),
+                                    ))
+                                }
+                                Err(e) => {
+                                    return Err((
+                                        14,
+                                        format!(
+                                            "{} {} cannot be found: {}",
+                                            POLL_PATH_LONG, dir, e
+                                        ),
+                                    ))
+                                }
+                            }
+                        }
+                    }
+                    POLL_FALLBACK_LONG => {
+                        result.poll_fallback =
+                            Some(duration_value_of(args, &mut i, None, POLL_FALLBACK_LONG)?)
+                    }
+                    MIN_PATHS_LONG => {
+                        result.min_paths = Some(count_value_of(args, &mut i, MIN_PATHS_LONG)?)
+                    }
+                    DROP_BELOW_MIN_PATHS_LONG => result.drop_below_min_paths = true,
+                    ACTIVE_HOURS_LONG => {
+                        let hours = value_of(args, &mut i, None, ACTIVE_HOURS_LONG, "HH:MM-HH:MM")?;
+                        match ActiveHours::parse(hours) {
+                            Ok(hours) => result.active_hours = Some(hours),
+                            Err(e) => {
+                                return Err((
+                                    14,
+                                    format!("Bad {} {}: {}", ACTIVE_HOURS_LONG, hours, e),
+                                ))
+                            }
+                        }
+                    }
+                    DROP_OUTSIDE_ACTIVE_HOURS_LONG => result.drop_outside_active_hours = true,
+                    WORKING_SET_LIMIT_LONG => {
+                        result.working_set_limit =
+                            Some(count_value_of(args, &mut i, WORKING_SET_LIMIT_LONG)?)
+                    }
+                    FLUSH_ON_LONG => {
+                        let file = value_of(args, &mut i, None, FLUSH_ON_LONG, "a file path")?;
+                        result.flush_on = Some(absolute_file_path(file, FLUSH_ON_LONG)?);
+                    }
+                    EXIT_ON_LONG => {
+                        let file = value_of(args, &mut i, None, EXIT_ON_LONG, "a file path")?;
+                        result.exit_on = Some(absolute_file_path(file, EXIT_ON_LONG)?);
+                    }
+                    CONFIG_LONG => {
+                        result.config =
+                            Some(value_of(args, &mut i, None, CONFIG_LONG, "a file path")?.clone())
+                    }
+                    LISTEN_LONG => {
+                        result.listen = Some(
+                            value_of(args, &mut i, None, LISTEN_LONG, "an address or socket path")?
+                                .clone(),
+                        )
+                    }
+                    JSON_FILE_LONG => {
+                        let file = value_of(args, &mut i, None, JSON_FILE_LONG, "a file")?;
+                        result.json_file = Some(file.clone());
+                    }
+                    FIFO_LONG => {
+                        let path = value_of(args, &mut i, None, FIFO_LONG, "a file path")?;
+                        result.fifo = Some(absolute_file_path(path, FIFO_LONG)?);
+                    }
+                    MIRROR_TO_LONG => {
+                        let path = value_of(args, &mut i, None, MIRROR_TO_LONG, "a directory")?;
+                        // Created when watching starts, if it does not exist yet
+                        result.mirror_to = Some(match fs::canonicalize(path) {
+                            Ok(real) if real.is_dir() => real,
+                            Ok(_) => {
+                                return Err((
+                                    14,
+                                    format!("{} {} is not a directory", MIRROR_TO_LONG, path),
+                                ))
+                            }
+                            Err(_) => absolute_file_path(path, MIRROR_TO_LONG)?,
+                        });
+                    }
+                    STDIN_TRIGGER_LONG => result.stdin_trigger = true,
+                    PAUSE_ON_ERROR_LONG => result.pause_on_error = true,
+                    CONFIRM_LONG => result.confirm = true,
+                    CONFIRM_TIMEOUT_LONG => {
+                        result.confirm_timeout =
+                            duration_value_of(args, &mut i, None, CONFIRM_TIMEOUT_LONG)?
+                    }
+                    EXPAND_ENV_LONG => result.expand_env = true,
+                    RECORD_LONG => {
+                        result.record =
+                            Some(value_of(args, &mut i, None, RECORD_LONG, "a file path")?.clone())
+                    }
+                    REPLAY_EVENTS_LONG => {
+                        result.replay_events = Some(
+                            value_of(args, &mut i, None, REPLAY_EVENTS_LONG, "a file path")?
+                                .clone(),
+                        )
+                    }
+                    REPLAY_SPEED_LONG => {
+                        let speed = value_of(args, &mut i, None, REPLAY_SPEED_LONG, "a number")?;
+                        match speed.parse::<f64>() {
+                            Ok(speed) if speed.is_finite() && speed >= 0.0 => {
+                                result.replay_speed = speed
+                            }
+                            _ => return Err((
+                                14,
+                                format!(
+                                    "{} must be a number of zero or more, such as 1 or 0.5, not '{}'",
+                                    REPLAY_SPEED_LONG, speed
+                                ),
+                            )),
+                        }
+                    }
+                    JITTER_SEED_LONG => {
+                        let seed = value_of(args, &mut i, None, JITTER_SEED_LONG, "a number")?;
+                        match seed.parse::<u64>() {
+                            Ok(seed) => result.jitter_seed = Some(seed),
+                            Err(_) => {
+                                return Err((
+                                    14,
+                                    format!(
+                                        "{} must be a whole number, not '{}'",
+                                        JITTER_SEED_LONG, seed
+                                    ),
+                                ))
+                            }
+                        }
+                    }
+                    JITTER_LONG => {
+                        result.jitter = Some(duration_value_of(args, &mut i, None, JITTER_LONG)?)
+                    }
+                    FAST_FIRST_LONG => {
+                        result.fast_first =
+                            Some(duration_value_of(args, &mut i, None, FAST_FIRST_LONG)?)
+                    }
+                    SETTLE_RATE_LONG => {
+                        result.settle_rate = Some(count_value_of(args, &mut i, SETTLE_RATE_LONG)?)
+                    }
+                    HEARTBEAT_LONG => {
+                        result.heartbeat =
+                            Some(duration_value_of(args, &mut i, None, HEARTBEAT_LONG)?)
+                    }
+                    INCLUDE_CHMOD_LONG => {
+                        result.chmod_files = true;
+                        result.chmod_dirs = true;
+                    }
+                    CHMOD_LONG => {
+                        let which =
+                            value_of(args, &mut i, None, CHMOD_LONG, "dirs, files, both or none")?;
+                        (result.chmod_dirs, result.chmod_files) = match which.as_str() {
+                            "dirs" => (true, false),
+                            "files" => (false, true),
+                            "both" => (true, true),
+                            "none" => (false, false),
+                            _ => {
+                                return Err((
+                                    14,
+                                    format!(
+                                        "{} must be dirs, files, both or none, not '{}'",
+                                        CHMOD_LONG, which
+                                    ),
+                                ))
+                            }
+                        };
+                    }
+                    PTY_LONG => result.pty = true,
+                    DIRS_ONLY_LONG => result.dirs_only = true,
+                    COMMAND_JSON_LONG => {
+                        let json = value_of(args, &mut i, None, COMMAND_JSON_LONG, "a JSON array")?;
+                        match serde_json::from_str::<Vec<String>>(json) {
+                            Ok(command) if !command.is_empty() => result.command = command,
+                            Ok(_) => {
+                                return Err((
+                                    14,
+                                    format!("{} must not be an empty array", COMMAND_JSON_LONG),
+                                ))
+                            }
+                            Err(e) => {
+                                return Err((
+                                    14,
+                                    format!(
+                                        "{} must be a JSON array of strings, not '{}': {}",
+                                        COMMAND_JSON_LONG, json, e
+                                    ),
+                                ))
+                            }
+                        }
+                    }
+                    COMMAND_TEMPLATE_LONG => {
+                        let file =
+                            value_of(args, &mut i, None, COMMAND_TEMPLATE_LONG, "a file path")?;
+                        let text = read_file(file, COMMAND_TEMPLATE_LONG)?;
+                        if let Err(e) = template::validate(&text) {
+                            return Err((
+                                14,
+                                format!("Invalid command template in {}: {}", file, e),
+                            ));
+                        }
+                        result.command_template = Some(text);
+                    }
+                    REWATCH_GRACE_LONG => {
+                        result.rewatch_grace =
+                            Some(duration_value_of(args, &mut i, None, REWATCH_GRACE_LONG)?)
+                    }
+                    FAILURE_COOLDOWN_LONG => {
+                        result.failure_cooldown = Some(duration_value_of(
+                            args,
+                            &mut i,
+                            None,
+                            FAILURE_COOLDOWN_LONG,
+                        )?)
+                    }
+                    JSON_SCHEMA_VERSION_LONG => {
+                        println!("{}", event::SCHEMA_VERSION);
+                        std::process::exit(0);
+                    }
+                    FILTER_SHORT | FILTER_LONG => {
                         if let Some(next) = args.get(i + 1) {
                             // Skip looking for a flag in the next one - it's our regex
                             i += 1;
                             match Regex::new(next) {
-                                Ok(rex) => result.filter = Some(rex),
-                                Err(e) => print_help_and_exit(
-                                    9,
-                                    Some(format!("Invalid regular expression '{}' - {}", next, e)),
-                                ),
+                                Ok(rex) => result.filter.push(rex),
+                                Err(e) => {
+                                    return Err((
+                                        9,
+                                        format!("Invalid regular expression '{}' - {}", next, e),
+                                    ))
+                                }
                             }
                         } else {
-                            print_help_and_exit(
+                            return Err((
                                 8,
-                                Some(format!(
+                                format!(
                                     "{}/{} must be followed by a regular expression argument",
                                     FILTER_SHORT, FILTER_LONG
-                                )),
-                            );
+                                ),
+                            ));
                         }
                     }
                     SECONDS_SHORT | SECONDS_LONG => {
                         if let Some(secs) = args.get(i + 1) {
                             // Skip looking for a flag in the next one - it's our value
                             i += 1;
-                            match secs.parse() {
-                                Ok(seconds) => {
-                                    if seconds == 0 {
-                                        print_help_and_exit(7, Some("Delay must be > 0"));
-                                    }
-                                    result.delay_seconds = seconds;
-                                }
-                                Err(_) => print_help_and_exit(
-                                    2,
-                                    Some(format!(
-                                        "Could not parse {}/{} string '{}' as an integer",
-                                        SECONDS_SHORT, SECONDS_LONG, secs
-                                    )),
-                                ),
-                            }
+                            result.delay_seconds = parse_seconds(
+                                secs,
+                                &format!("{}/{}", SECONDS_SHORT, SECONDS_LONG),
+                            )?;
                         } else {
-                            print_help_and_exit(
+                            return Err((
                                 3,
-                                Some(format!(
+                                format!(
                                     "{}/{} must be followed by an integer",
                                     SECONDS_SHORT, SECONDS_LONG
-                                )),
-                            );
+                                ),
+                            ));
                         }
                     }
                     DIR_SHORT | DIR_LONG => {
                         if let Some(d) = args.get(i + 1) {
                             // Skip looking for a flag in the next one - it's our directory
                             i += 1;
+                            let d = &checked_expand_env(d)?;
                             let pth = fs::canonicalize(std::path::PathBuf::from(d));
                             match pth {
                                 Ok(path) => {
                                     debug!("Target path {} canonicalized to {:?}", d, path);
                                     result.path = path.to_str().unwrap().to_string();
+                                    result.given_path = Some(d.clone());
                                 }
                                 Err(e) => {
                                     error!("Could not canonicalize '{}' : {}", d, e);
-                                    print_help_and_exit(
+                                    return Err((
                                         6,
-                                        Some(format!(
+                                        format!(
                                             "Target folder {} cannot be canonicalized: {}",
                                             d, e
-                                        )),
-                                    );
+                                        ),
+                                    ));
                                 }
                             }
                         } else {
                             info!("Unrecognized argument at {}: '{}' - assume it is start of command to run.", i, arg);
-                            print_help_and_exit(
+                            return Err((
                                 5,
-                                Some(format!(
+                                format!(
                                     "{}/{} must be followed by a file path",
                                     DIR_SHORT, DIR_LONG
-                                )),
-                            );
+                                ),
+                            ));
                         }
                     }
                     _ => {
@@ -354,9 +2564,42 @@ impl Args {
                 break;
             }
         }
+        Ok(result)
+    }
+
+    /// Validate the combination of arguments and fill in derived defaults
+    fn finish(self) -> Args {
+        let mut result = self;
+        if let Some(to) = &result.status_to {
+            status::set(status_sink(to));
+        }
+        if let Some(file) = &result.run_log {
+            if let Err(e) = runlog::open(file) {
+                print_help_and_exit(
+                    15,
+                    Some(format!("Could not open {} file '{}': {}", RUN_LOG_LONG, file, e)),
+                );
+            }
+        }
         if result.help {
             print_help_and_exit::<String>(0, None);
-            return result;
+        }
+        if result.help_json {
+            print_help_json();
+            std::process::exit(0);
+        }
+        if result.filter_component {
+            // Anchored, so a regex only matches a whole component
+            result.filter = result
+                .filter
+                .iter()
+                .map(|rex| {
+                    Regex::new(&format!("^(?:{})$", rex)).expect("anchoring keeps a regex valid")
+                })
+                .collect();
+        }
+        if result.expand_env && !result.shell {
+            result.command = result.command.iter().map(|c| or_exit(checked_expand_env(c))).collect();
         }
         if result.relativize_paths && !result.pass_changed_paths {
             print_help_and_exit(
@@ -370,53 +2613,294 @@ impl Args {
                 )),
             );
         }
-        if result.command.is_empty() {
-            eprintln!("No command passed - will use `echo`");
-            result.pass_changed_paths = true;
-            result.shell = true;
-            result.command = vec![String::from("echo")];
-        }
-        if DEFAULT_PATH == result.path.as_str() {
-            let pth = fs::canonicalize(result.path.as_str());
-            match pth {
-                Ok(real_path) => {
-                    result.path = real_path
-                        .to_str()
-                        .expect("Could not convert path to a string")
-                        .to_string()
-                }
-                Err(e) => {
-                    print_help_and_exit(
-                        6,
-                        Some(format!("Working directory . no longer exists? {:?}", e)),
-                    );
-                }
-            }
+        if result.raw_shell && !result.shell {
+            print_help_and_exit(
+                4,
+                Some(format!(
+                    "Can only use {} if {}/{} is also set.",
+                    RAW_SHELL_LONG, SHELL_SHORT, SHELL_LONG
+                )),
+            );
         }
-        result
-    }
+        if result.stable_poll.is_some() && result.stable_for.is_none() {
+            print_help_and_exit(
+                4,
+                Some(format!(
+                    "Can only use {} if {} is also set.",
+                    STABLE_POLL_LONG, STABLE_FOR_LONG
+                )),
+            );
+        }
+        if result.timestamp_format.is_some() && !result.timestamp_output {
+            print_help_and_exit(
+                4,
+                Some(format!(
+                    "Can only use {} if {} is also set.",
+                    TIMESTAMP_FORMAT_LONG, TIMESTAMP_OUTPUT_LONG
+                )),
+            );
+        }
+        if result.replay_speed > 0.0 && result.replay_events.is_none() {
+            print_help_and_exit(
+                4,
+                Some(format!(
+                    "Can only use {} if {} is also set.",
+                    REPLAY_SPEED_LONG, REPLAY_EVENTS_LONG
+                )),
+            );
+        }
+        if result.drop_below_min_paths && result.min_paths.is_none() {
+            print_help_and_exit(
+                4,
+                Some(format!(
+                    "Can only use {} if {} is also set.",
+                    DROP_BELOW_MIN_PATHS_LONG, MIN_PATHS_LONG
+                )),
+            );
+        }
+        if result.drop_outside_active_hours && result.active_hours.is_none() {
+            print_help_and_exit(
+                4,
+                Some(format!(
+                    "Can only use {} if {} is also set.",
+                    DROP_OUTSIDE_ACTIVE_HOURS_LONG, ACTIVE_HOURS_LONG
+                )),
+            );
+        }
+        if !result.env_files.is_empty() {
+            // Applied in order, so --env and then later files win
+            let mut env = Vec::new();
+            for file in &result.env_files {
+                let text = or_exit(read_file(file, ENV_FILE_LONG));
+                match dotenv::parse(&text) {
+                    Ok(vars) => env.extend(vars),
+                    Err(e) => print_help_and_exit(
+                        14,
+                        Some(format!("Invalid {} file '{}': {}", ENV_FILE_LONG, file, e)),
+                    ),
+                }
+            }
+            env.append(&mut result.env);
+            result.env = env;
+        }
+        if result.confirm && !std::io::stdin().is_terminal() {
+            status_eprintln!("stdin is not a terminal - ignoring {}", CONFIRM_LONG);
+            result.confirm = false;
+        }
+        if result.config_reload && result.config_file.is_none() {
+            print_help_and_exit(
+                4,
+                Some(format!(
+                    "{} needs a configuration file, and none was found",
+                    CONFIG_RELOAD_LONG
+                )),
+            );
+        }
+        if result.command.is_empty()
+            && !result.json
+            && !result.no_run
+            && result.listen.is_none()
+            && result.json_file.is_none()
+            && result.fifo.is_none()
+            && result.mirror_to.is_none()
+            && result.command_template.is_none()
+            && !result.has_change_commands()
+        {
+            status_eprintln!("No command passed - will use `echo`");
+            result.pass_changed_paths = true;
+            result.shell = true;
+            result.command = vec![String::from("echo")];
+        }
+        if let Err((code, message)) = result.locate_dir() {
+            print_help_and_exit(code, Some(message));
+        }
+        if result.rust {
+            result.apply_rust_preset();
+        }
+        if let Err((code, message)) = result.compile_patterns() {
+            print_help_and_exit(code, Some(message));
+        }
+        result
+    }
+
+    /// With --no-canonicalize, spell the files watchfs compares changed paths against the way
+    /// the watcher will report them - through the watched directory as given
+    fn respell(&mut self, real: &Path, literal: &Path) {
+        let respell = |path: &mut PathBuf| {
+            if let Ok(rest) = path.strip_prefix(real) {
+                *path = literal.join(rest);
+            }
+        };
+        self.touch_on_success.iter_mut().for_each(respell);
+        self.touch_on_failure.iter_mut().for_each(respell);
+        self.flush_on.iter_mut().for_each(respell);
+        self.exit_on.iter_mut().for_each(respell);
+        self.mirror_to.iter_mut().for_each(respell);
+        self.config_file.iter_mut().for_each(respell);
+        self.watch_list.iter_mut().for_each(respell);
+        self.poll_paths.iter_mut().for_each(respell);
+    }
+
+    /// Seed the settings --rust implies; explicit -f/--filter regexes replace the preset one
+    fn apply_rust_preset(&mut self) {
+        let dir = self.dir();
+        self.only_under = RUST_PRESET_PATHS.iter().map(|p| dir.join(p)).collect();
+        self.excluded = vec![dir.join(RUST_PRESET_EXCLUDE)];
+        if self.filter.is_empty() {
+            self.filter.push(Regex::new(RUST_PRESET_FILTER).expect("Bad preset regex"));
+        }
+        if self.pass_changed_paths {
+            self.relativize_paths = true;
+        }
+        debug!(
+            "Rust preset: only {:?}, excluding {:?}, filter {:?}",
+            self.only_under, self.excluded, self.filter
+        );
+    }
 }
 
 impl Display for Args {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("path: {}, command: {:?}, delay_seconds:{}, non_recursive:{}, pass_changed_paths:{}, relativize_paths:{}, shell:{}, once:{}, exit_on_error:{}, verbose:{}, help:{}, filter:{:?}", 
+        f.write_fmt(format_args!("path: {}, given_path:{:?}, no_canonicalize:{}, literal_dir:{:?}, canonical_dir:{:?}, command: {:?}, delay_seconds:{}, non_recursive:{}, one_filesystem:{}, owner:{:?}, prune_dirs:{:?}, pass_changed_paths:{}, pass_dir:{}, relativize_paths:{}, slash:{:?}, shell:{}, once:{}, exit_on_error:{}, max_failures:{:?}, success_codes:{:?}, verbose:{}, verbosity:{}, help:{}, help_json:{}, filter:{:?}, preserve_order:{}, json:{}, failure_cooldown:{:?}, stdin_trigger:{}, chmod_files:{}, chmod_dirs:{}, rewatch_grace:{:?}, pty:{}, dirs_only:{}, command_template:{:?}, pause_on_error:{}, expand_env:{}, heartbeat:{:?}, jitter:{:?}, fast_first:{:?}, settle_rate:{:?}, jitter_seed:{:?}, record:{:?}, replay_events:{:?}, replay_speed:{}, listen:{:?}, json_file:{:?}, fifo:{:?}, mirror_to:{:?}, no_run:{}, kill_children_on_exit:{}, child_pidfile:{:?}, rust:{}, only_under:{:?}, excluded:{:?}, config:{:?}, print_watched_paths:{}, debug_filter:{}, flush_on:{:?}, exit_on:{:?}, tag_streams:{}, timestamp_output:{}, timestamp_format:{:?}, max_events_per_second:{:?}, working_set_limit:{:?}, min_paths:{:?}, drop_below_min_paths:{}, active_hours:{:?}, drop_outside_active_hours:{}, poll_fallback:{:?}, poll_paths:{:?}, emit_empty:{}, watch_list:{:?}, also_watch:{:?}, stdin_file:{:?}, resolve_symlink_targets:{}, max_output_bytes:{:?}, json_pretty:{}, filter_basename:{}, filter_component:{}, max_watchers:{:?}, debounce_for:{:?}, status_to:{:?}, warm_up:{:?}, warm_up_interval:{:?}, warm_up_attempts:{}, paths_as_response_file:{}, response_file_prefix:{:?}, after_quiet_only:{:?}, stable_for:{:?}, stable_poll:{:?}, skip_duplicate_batches:{:?}, show_timing:{}, announce:{}, detach:{}, max_detached:{:?}, touch_on_success:{:?}, touch_on_failure:{:?}, env:{:?}, env_files:{:?}, clean_env:{}, scratch_dir:{}, scratch:{:?}, umask:{:?}, bell_on_error:{}, bell_on_success:{}, config_reload:{}, restart_on_config_change:{}, init:{}, force:{}, config_file:{:?}, by_dir:{}, canonical_paths:{}, keep_missing:{}, existing_only:{}, deletions_only:{}, restart:{}, stop_signal:{}, stop_timeout:{:?}, include_from:{:?}, exclude_from:{:?}, pattern_regex:{}, includes:{:?}, excludes:{:?}, on_create:{:?}, on_modify:{:?}, on_delete:{:?}, raw_shell:{}, run_log:{:?}, confirm:{}, confirm_timeout:{:?}", 
             self.path,
+            self.given_path,
+            self.no_canonicalize,
+            self.literal_dir,
+            self.canonical_dir,
             self.command,
             self.delay_seconds,
             self.non_recursive,
+            self.one_filesystem,
+            self.owner,
+            self.prune_dirs,
             self.pass_changed_paths,
+            self.pass_dir,
             self.relativize_paths,
+            self.slash,
             self.shell,
             self.once,
             self.exit_on_error,
+            self.max_failures,
+            self.success_codes,
             self.verbose,
+            self.verbosity,
             self.help,
+            self.help_json,
             self.filter,
+            self.preserve_order,
+            self.json,
+            self.failure_cooldown,
+            self.stdin_trigger,
+            self.chmod_files,
+            self.chmod_dirs,
+            self.rewatch_grace,
+            self.pty,
+            self.dirs_only,
+            self.command_template,
+            self.pause_on_error,
+            self.expand_env,
+            self.heartbeat,
+            self.jitter,
+            self.fast_first,
+            self.settle_rate,
+            self.jitter_seed,
+            self.record,
+            self.replay_events,
+            self.replay_speed,
+            self.listen,
+            self.json_file,
+            self.fifo,
+            self.mirror_to,
+            self.no_run,
+            self.kill_children_on_exit,
+            self.child_pidfile,
+            self.rust,
+            self.only_under,
+            self.excluded,
+            self.config,
+            self.print_watched_paths,
+            self.debug_filter,
+            self.flush_on,
+            self.exit_on,
+            self.tag_streams,
+            self.timestamp_output,
+            self.timestamp_format,
+            self.max_events_per_second,
+            self.working_set_limit,
+            self.min_paths,
+            self.drop_below_min_paths,
+            self.active_hours,
+            self.drop_outside_active_hours,
+            self.poll_fallback,
+            self.poll_paths,
+            self.emit_empty,
+            self.watch_list,
+            self.also_watch,
+            self.stdin_file,
+            self.resolve_symlink_targets,
+            self.max_output_bytes,
+            self.json_pretty,
+            self.filter_basename,
+            self.filter_component,
+            self.max_watchers,
+            self.debounce_for,
+            self.status_to,
+            self.warm_up,
+            self.warm_up_interval,
+            self.warm_up_attempts,
+            self.paths_as_response_file,
+            self.response_file_prefix,
+            self.after_quiet_only,
+            self.stable_for,
+            self.stable_poll,
+            self.skip_duplicate_batches,
+            self.show_timing,
+            self.announce,
+            self.detach,
+            self.max_detached,
+            self.touch_on_success,
+            self.touch_on_failure,
+            self.env,
+            self.env_files,
+            self.clean_env,
+            self.scratch_dir,
+            self.scratch,
+            self.umask,
+            self.bell_on_error,
+            self.bell_on_success,
+            self.config_reload,
+            self.restart_on_config_change,
+            self.init,
+            self.force,
+            self.config_file,
+            self.by_dir,
+            self.canonical_paths,
+            self.keep_missing,
+            self.existing_only,
+            self.deletions_only,
+            self.restart,
+            self.stop_signal,
+            self.stop_timeout,
+            self.include_from,
+            self.exclude_from,
+            self.pattern_regex,
+            self.includes,
+            self.excludes,
+            self.on_create,
+            self.on_modify,
+            self.on_delete,
+            self.raw_shell,
+            self.run_log,
+            self.confirm,
+            self.confirm_timeout,
         ))
     }
 }
 
-fn maybe_quote_or_escape(st: &String) -> String {
+pub(crate) fn maybe_quote_or_escape(st: &String) -> String {
+    if cfg!(target_os = "windows") {
+        return quote_for_cmd(st);
+    }
     if st.contains(' ') || st.contains('\n') || st.contains('\t') {
         let mut result = String::new();
         let quote_char = if st.contains('$') || st.contains('\'') {
@@ -434,6 +2918,109 @@ fn maybe_quote_or_escape(st: &String) -> String {
     }
 }
 
+/// Quote and escape an argument for `cmd /C`, whose rules differ from unix shells:
+/// arguments containing spaces or metacharacters are double quoted with any embedded
+/// double quotes doubled, and `%` is caret-escaped (outside the quotes, since carets are
+/// literal inside them) so `%VAR%` is not expanded
+fn quote_for_cmd(st: &str) -> String {
+    let needs_quotes = st.is_empty()
+        || st.contains(|c: char| {
+            c.is_whitespace() || matches!(c, '&' | '|' | '<' | '>' | '^' | '(' | ')' | '"')
+        });
+    let mut result = String::with_capacity(st.len() + 2);
+    if needs_quotes {
+        result.push('"');
+        for c in st.chars() {
+            match c {
+                '"' => result.push_str("\"\""),
+                '%' => result.push_str("\"^%\""),
+                c => result.push(c),
+            }
+        }
+        result.push('"');
+    } else {
+        for c in st.chars() {
+            if c == '%' {
+                result.push('^');
+            }
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// A new temporary directory path for a run with --scratch-dir
+fn scratch_dir_path() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!(
+        "watchfs-scratch-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// A new temporary file path for passing the changed paths with --paths-as-response-file
+fn response_file_path() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!(
+        "watchfs-paths-{}-{}.txt",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Creates a command which runs the passed string in the platform's shell
+fn shell_command(line: String) -> Command {
+    if cfg!(target_os = "windows") {
+        let mut result = Command::new("cmd");
+        result.arg("/C");
+        // cmd does its own parsing, so the normal Windows argument quoting must
+        // not be applied on top of ours
+        raw_arg(&mut result, line);
+        result
+    } else {
+        let mut result = Command::new("sh");
+        result.arg("-c");
+        result.arg(line);
+        result
+    }
+}
+
+#[cfg(unix)]
+fn own_process_group(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    cmd.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn own_process_group(_cmd: &mut Command) {}
+
+#[cfg(unix)]
+fn set_umask(cmd: &mut Command, mask: u32) {
+    use std::os::unix::process::CommandExt;
+    // Safe - umask cannot fail, and is async-signal-safe
+    unsafe {
+        cmd.pre_exec(move || {
+            libc::umask(mask as libc::mode_t);
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn set_umask(_cmd: &mut Command, _mask: u32) {}
+
+#[cfg(windows)]
+fn raw_arg(cmd: &mut Command, arg: String) {
+    use std::os::windows::process::CommandExt;
+    cmd.raw_arg(arg);
+}
+
+#[cfg(not(windows))]
+fn raw_arg(cmd: &mut Command, arg: String) {
+    cmd.arg(arg);
+}
+
 #[inline]
 fn println<A: AsRef<std::ffi::OsStr>>(err: bool, str: A) {
     // Ensure we don't pollute stdout with help content if the context is error-exit
@@ -444,6 +3031,804 @@ fn println<A: AsRef<std::ffi::OsStr>>(err: bool, str: A) {
     }
 }
 
+/// Describes one command-line option, for rendering help in its various forms
+pub(crate) struct OptionInfo {
+    pub short: Option<&'static str>,
+    pub long: &'static str,
+    /// The name of the value the option takes, if it takes one
+    pub value: Option<&'static str>,
+    /// The description - may contain newlines, which are indented when printing help
+    pub description: String,
+}
+
+impl OptionInfo {
+    fn new(
+        short: Option<&'static str>,
+        long: &'static str,
+        value: Option<&'static str>,
+        description: impl Into<String>,
+    ) -> Self {
+        Self {
+            short,
+            long,
+            value,
+            description: description.into(),
+        }
+    }
+
+    /// The flag names and value as shown in the left column of the help
+    fn synopsis(&self) -> String {
+        let mut result = String::from(" ");
+        if let Some(short) = self.short {
+            result.push_str(short);
+            result.push(' ');
+        } else {
+            // Line up long-only options with the long names of those that have both
+            result.push_str("   ");
+        }
+        result.push_str(self.long);
+        if let Some(value) = self.value {
+            result.push(' ');
+            result.push_str(value);
+        }
+        result
+    }
+}
+
+/// All of the options we support, in the order they should appear in the help
+pub(crate) fn options() -> Vec<OptionInfo> {
+    vec![
+        OptionInfo::new(
+            Some(DIR_SHORT),
+            DIR_LONG,
+            Some("d"),
+            format!(
+                "The directory to watch (default {}); $VAR and ${{VAR}} are expanded[4]",
+                DEFAULT_PATH
+            ),
+        ),
+        OptionInfo::new(
+            None,
+            NO_CANONICALIZE_LONG,
+            None,
+            "Watch the directory by the path given, made absolute but with any\nsymlinks in it kept, rather than by its canonical path; changed paths\nare passed (and relativized) the same way",
+        ),
+        OptionInfo::new(
+            Some(SECONDS_SHORT),
+            SECONDS_LONG,
+            Some("n"),
+            format!(
+                "The number of seconds to wait for changes to cease before running the\ncommand (default ${}, or {})",
+                DELAY_ENV, DEFAULT_DELAY_SECONDS
+            ),
+        ),
+        OptionInfo::new(
+            None,
+            FAST_FIRST_LONG,
+            Some("t"),
+            "Wait only this long, instead of the delay, before running the first\nbatch of changes after startup - so saving a file right away gets a\nquick response[3]",
+        ),
+        OptionInfo::new(
+            None,
+            SETTLE_RATE_LONG,
+            Some("n"),
+            "Run the command once fewer than n changes have arrived in the last\nsecond (and at least a second after the batch began), rather than\nwaiting for the delay - which still applies if it passes first",
+        ),
+        OptionInfo::new(
+            None,
+            AFTER_QUIET_ONLY_LONG,
+            Some("t"),
+            "Once the delay has passed, also wait until no events at all - even\nfiltered out ones - have arrived for this long; for slow or network\nfilesystems where changes trickle in[3]",
+        ),
+        OptionInfo::new(
+            None,
+            STABLE_FOR_LONG,
+            Some("t"),
+            "Once the delay has passed, also wait until no changed file has changed\nsize or modification time for this long, so files still being written\nare not run on half-written[3]",
+        ),
+        OptionInfo::new(
+            None,
+            STABLE_POLL_LONG,
+            Some("t"),
+            format!(
+                "How often to look at the changed files while waiting for them to be\nstable with {} (default {}ms)[3]",
+                STABLE_FOR_LONG, DEFAULT_STABLE_POLL_MILLIS
+            ),
+        ),
+        OptionInfo::new(
+            None,
+            SKIP_DUPLICATE_BATCHES_LONG,
+            Some("t"),
+            "Do not run the command for a batch of exactly the same paths as the\nlast successful run, if that was less than t ago - for tools which\nrewrite the same files repeatedly; skipped batches do not extend it[3]",
+        ),
+        OptionInfo::new(
+            None,
+            SHOW_TIMING_LONG,
+            None,
+            "With each batch, print how long it waited since its first change and\nhow many later changes pushed its run back - for tuning -s",
+        ),
+        OptionInfo::new(
+            None,
+            ANNOUNCE_LONG,
+            None,
+            "Before each run, print a line saying what is being run for how many\nchanged paths, naming the first few (bold on a terminal unless NO_COLOR\nis set)",
+        ),
+        OptionInfo::new(
+            None,
+            DEBOUNCE_FOR_LONG,
+            Some("ext=t"),
+            "Wait this long, instead of -s, after changes to files with this\nextension, e.g. css=200ms; the longest delay of any file in a batch of\nchanges applies to it[3][5]",
+        ),
+        OptionInfo::new(
+            None,
+            JITTER_LONG,
+            Some("t"),
+            "Add a random amount of time, up to this, to the delay - staggers runs\nof many instances reacting to the same changes[3]",
+        ),
+        OptionInfo::new(
+            None,
+            JITTER_SEED_LONG,
+            Some("n"),
+            "Seed the --jitter offsets with this number, so the same sequence of\ndelays is used every time - for tests and coordinated deployments",
+        ),
+        OptionInfo::new(
+            Some(SHELL_SHORT),
+            SHELL_LONG,
+            None,
+            "Execute the command in a shell (`sh -c` on unix, `cmd /C` on windows)",
+        ),
+        OptionInfo::new(
+            None,
+            RAW_SHELL_LONG,
+            None,
+            "With -l, join the command's arguments with spaces as they are, without\nquoting them, so quoting and expansion are left to the shell; changed\npaths are still quoted",
+        ),
+        OptionInfo::new(
+            None,
+            PTY_LONG,
+            None,
+            "Run the command attached to a pseudo-terminal, so tools which only\ncolorize output on a terminal do so",
+        ),
+        OptionInfo::new(
+            None,
+            COMMAND_TEMPLATE_LONG,
+            Some("file"),
+            "Render this template for each batch, and run the result in the shell,\ninstead of a command; see the README for the template variables",
+        ),
+        OptionInfo::new(
+            None,
+            COMMAND_JSON_LONG,
+            Some("json"),
+            "The command to run, as a JSON array of strings - e.g.\n'[\"cargo\",\"build\"]' - for programs which start watchfs and should\nnot need to quote arguments; a command after the options replaces it",
+        ),
+        OptionInfo::new(
+            None,
+            EXPAND_ENV_LONG,
+            None,
+            "Expand $VAR and ${VAR} in the command's arguments without needing -l[4]",
+        ),
+        OptionInfo::new(
+            None,
+            TOUCH_ON_SUCCESS_LONG,
+            Some("file"),
+            "Create the file, or update its modification time, each time the command\nsucceeds; changes to it are never passed on",
+        ),
+        OptionInfo::new(
+            None,
+            TOUCH_ON_FAILURE_LONG,
+            Some("file"),
+            "Create the file, or update its modification time, each time the command\nfails or cannot be started; changes to it are never passed on",
+        ),
+        OptionInfo::new(
+            None,
+            RESTART_LONG,
+            None,
+            "If the command is still running when the next batch of changes is\nready, stop it and start it again - e.g. for servers; overrides\n--detach, and is ignored with --pty",
+        ),
+        OptionInfo::new(
+            None,
+            STOP_SIGNAL_LONG,
+            Some("sig"),
+            "With --restart, the signal to stop the command (and anything it\nstarted) with - SIGTERM (the default), SIGINT, SIGHUP, SIGQUIT, SIGKILL,\nSIGUSR1 or SIGUSR2; on Windows, the command is always forcibly ended",
+        ),
+        OptionInfo::new(
+            None,
+            STOP_TIMEOUT_LONG,
+            Some("t"),
+            "With --restart, how long to wait for the command to exit after\nsignalling it before killing it with SIGKILL (default 10s)[3]",
+        ),
+        OptionInfo::new(
+            None,
+            BELL_ON_ERROR_LONG,
+            None,
+            "Ring the terminal bell when the command fails or cannot be started\n(only if stderr is a terminal)",
+        ),
+        OptionInfo::new(
+            None,
+            BELL_ON_SUCCESS_LONG,
+            None,
+            "Ring the terminal bell when the command succeeds (only if stderr is a\nterminal)",
+        ),
+        OptionInfo::new(
+            None,
+            ON_CREATE_LONG,
+            Some("cmd"),
+            "A shell command to run, instead of the command, for paths which were\ncreated or renamed into place in a batch[6]",
+        ),
+        OptionInfo::new(
+            None,
+            ON_MODIFY_LONG,
+            Some("cmd"),
+            "A shell command to run, instead of the command, for paths which were\nmodified in a batch[6]",
+        ),
+        OptionInfo::new(
+            None,
+            ON_DELETE_LONG,
+            Some("cmd"),
+            "A shell command to run, instead of the command, for paths which were\ndeleted or renamed away in a batch[6]",
+        ),
+        OptionInfo::new(
+            None,
+            DETACH_LONG,
+            None,
+            "Start the command without waiting for it to exit, so several runs can\noverlap; -x and -o apply when a run exits, but --failure-cooldown and\n--pause-on-error never see failures; ignored with --pty",
+        ),
+        OptionInfo::new(
+            None,
+            MAX_DETACHED_LONG,
+            Some("n"),
+            "With --detach, run at most this many copies of the command at once,\nwaiting for one to exit before starting another",
+        ),
+        OptionInfo::new(
+            None,
+            ENV_LONG,
+            Some("NAME=value"),
+            "Set an environment variable for the command; may be repeated",
+        ),
+        OptionInfo::new(
+            None,
+            ENV_FILE_LONG,
+            Some("file"),
+            "Set the environment variables in this dotenv file (NAME=value lines, see\nREADME) for the command; --env overrides them, and later files earlier\nones; may be repeated",
+        ),
+        OptionInfo::new(
+            None,
+            CLEAN_ENV_LONG,
+            None,
+            "Run the command with no environment variables but those set with\n--env - including PATH, so pass --env PATH=... or the command's full path",
+        ),
+        OptionInfo::new(
+            None,
+            SCRATCH_DIR_LONG,
+            None,
+            "Create an empty temporary directory for each run, removed when the\ncommand exits, passing its path in $WATCHFS_SCRATCH and in place of\n{scratch} in the command's arguments",
+        ),
+        OptionInfo::new(
+            None,
+            UMASK_LONG,
+            Some("mask"),
+            "Run the command with this octal umask, e.g. 022 (unix only; ignored\nwith --pty)",
+        ),
+        OptionInfo::new(
+            None,
+            KILL_CHILDREN_ON_EXIT_LONG,
+            None,
+            "If watchfs exits or is killed while the command is running, terminate\nthe command and anything it started",
+        ),
+        OptionInfo::new(
+            None,
+            CHILD_PIDFILE_LONG,
+            Some("file"),
+            "Write the process id of the running command to this file, replacing it\nwhen --restart starts a new one and removing it when the command exits\n(ignored with --pty)",
+        ),
+        OptionInfo::new(
+            Some(PASS_CHANGED_PATHS_SHORT),
+            PASS_CHANGED_PATHS_LONG,
+            None,
+            "Pass paths to files that changed as arguments to the command",
+        ),
+        OptionInfo::new(
+            None,
+            PASS_DIR_LONG,
+            None,
+            "Pass the watched directory (absolute, even with -r) as an argument to\nthe command, after its own arguments and before any changed paths - so\nit is $1 to a script",
+        ),
+        OptionInfo::new(
+            None,
+            PATHS_AS_RESPONSE_FILE_LONG,
+            None,
+            "Write the changed paths to a temporary file, one per line, and pass\n@file as a single argument instead, for tools which take response files",
+        ),
+        OptionInfo::new(
+            None,
+            CANONICAL_PATHS_LONG,
+            None,
+            "Pass each changed file once, by its canonical path, however the events\nspelled it; files which no longer exist are left out",
+        ),
+        OptionInfo::new(
+            None,
+            KEEP_MISSING_LONG,
+            None,
+            "With --canonical-paths, pass files which no longer exist as they were\nreported, instead of leaving them out",
+        ),
+        OptionInfo::new(
+            None,
+            EXISTING_ONLY_LONG,
+            None,
+            "Pass only changed paths which still exist when the command runs,\nleaving out deleted ones (even if created earlier in the batch);\noverrides --deletions-only",
+        ),
+        OptionInfo::new(
+            None,
+            DELETIONS_ONLY_LONG,
+            None,
+            "Pass only changed paths which no longer exist when the command runs\n(even if created earlier in the batch), implying --keep-missing;\noverrides --existing-only",
+        ),
+        OptionInfo::new(
+            None,
+            BY_DIR_LONG,
+            None,
+            "Pass (and report with --json) the distinct directories containing the\nchanged files instead of the files - e.g. to rebuild whole packages",
+        ),
+        OptionInfo::new(
+            None,
+            RESPONSE_FILE_PREFIX_LONG,
+            Some("text"),
+            format!(
+                "What precedes the file name passed with --paths-as-response-file\n(default {}; may be empty)",
+                DEFAULT_RESPONSE_FILE_PREFIX
+            ),
+        ),
+        OptionInfo::new(
+            Some(RELATIVIZE_SHORT),
+            RELATIVIZE_LONG,
+            None,
+            "Make paths to changed files relative to the directory being watched,\nor to the deepest --also-watch directory containing them",
+        ),
+        OptionInfo::new(
+            None,
+            SLASH_LONG,
+            Some("style"),
+            "The separator in paths passed to the command and in --json records -\nforward (/), backward (\\) or native (the default); on unix, a backslash\nin a file name is left alone",
+        ),
+        OptionInfo::new(
+            Some(FILTER_SHORT),
+            FILTER_LONG,
+            Some("regexp"),
+            "Only notify about file paths that match this regular expression\n(matches against the fully qualified path, regardless of -r); may be\nrepeated, in which case a path matching any of them is accepted (commas\nare part of the regex, not separators)[1]",
+        ),
+        OptionInfo::new(
+            None,
+            FILTER_BASENAME_LONG,
+            None,
+            "Match -f/--filter regexes against just the file name (e.g. ^main\\.rs$)\ninstead of the fully qualified path",
+        ),
+        OptionInfo::new(
+            None,
+            FILTER_COMPONENT_LONG,
+            None,
+            "Match -f/--filter regexes against each component of the path relative\nto the watched directory, accepting it if one matches whole - so -f src\nmatches src/main.rs but not mysrc/main.rs; overrides --filter-basename",
+        ),
+        OptionInfo::new(
+            None,
+            INCLUDE_FROM_LONG,
+            Some("file"),
+            "Also accept paths matching any glob in this file, one per line, matched\nagainst the path relative to the watched directory (see README)[5]",
+        ),
+        OptionInfo::new(
+            None,
+            EXCLUDE_FROM_LONG,
+            Some("file"),
+            "Ignore paths matching any glob in this file, one per line, even if -f\nor --include-from accepts them (see README)[5]",
+        ),
+        OptionInfo::new(
+            None,
+            PATTERN_SYNTAX_LONG,
+            Some("syntax"),
+            "Whether the lines of --include-from and --exclude-from files are globs\n(glob, the default) or regular expressions (regex)[1]",
+        ),
+        OptionInfo::new(
+            None,
+            PAUSE_ON_ERROR_LONG,
+            None,
+            "After the command fails, keep collecting changes but do not run it\nagain until Enter is pressed",
+        ),
+        OptionInfo::new(
+            None,
+            CONFIRM_LONG,
+            None,
+            "Before each run, print the command and the changed paths and ask\nwhether to run it, skipping the batch unless the answer is y; ignored\nif stdin is not a terminal",
+        ),
+        OptionInfo::new(
+            None,
+            CONFIRM_TIMEOUT_LONG,
+            Some("t"),
+            "With --confirm, how long to wait for an answer before taking it as\nno (default 30s)[3]",
+        ),
+        OptionInfo::new(
+            None,
+            INCLUDE_CHMOD_LONG,
+            None,
+            "Treat permission-only changes (e.g. chmod +x) as changes; some platforms\nreport these often, so this may increase noise (see README)",
+        ),
+        OptionInfo::new(
+            None,
+            CHMOD_LONG,
+            Some("which"),
+            "Which permission-only changes count as changes - to dirs, files, both\n(like --include-chmod) or none (the default)",
+        ),
+        OptionInfo::new(
+            None,
+            REWATCH_GRACE_LONG,
+            Some("t"),
+            "If the watched directory is deleted or replaced (e.g. by an atomic\nrename during a deploy), wait this long, then watch it again[3]",
+        ),
+        OptionInfo::new(
+            None,
+            CONFIG_LONG,
+            Some("file"),
+            "Read default arguments from this TOML file, instead of the nearest\n.watchfs.toml in the working directory or its parents (see README)",
+        ),
+        OptionInfo::new(
+            None,
+            CONFIG_RELOAD_LONG,
+            None,
+            "Watch the configuration file too, and apply changes to its delay,\nfilters and command without restarting",
+        ),
+        OptionInfo::new(
+            None,
+            GRACEFUL_RESTART_LONG,
+            None,
+            "Implies --config-reload, and also applies changes to the watched\ndirectory, --watch-list, --also-watch, --poll-path, --non-recursive and\n--resolve-symlink-targets, rebuilding the watcher in place",
+        ),
+        OptionInfo::new(
+            None,
+            INIT_LONG,
+            None,
+            "Write a .watchfs.toml describing every option, all commented out, to\nthe working directory and exit",
+        ),
+        OptionInfo::new(
+            None,
+            FORCE_LONG,
+            None,
+            "With --init, replace an existing .watchfs.toml",
+        ),
+        OptionInfo::new(
+            None,
+            WARM_UP_LONG,
+            Some("cmd"),
+            "Before watching, run this shell command until it succeeds - e.g. to\nwait for a server the command needs to be up",
+        ),
+        OptionInfo::new(
+            None,
+            WARM_UP_INTERVAL_LONG,
+            Some("t"),
+            format!(
+                "How long to wait between --warm-up attempts (default {}s)[3]",
+                DEFAULT_WARM_UP_INTERVAL_SECONDS
+            ),
+        ),
+        OptionInfo::new(
+            None,
+            WARM_UP_ATTEMPTS_LONG,
+            Some("n"),
+            format!(
+                "How many times to try the --warm-up command before exiting\n(default {})",
+                DEFAULT_WARM_UP_ATTEMPTS
+            ),
+        ),
+        OptionInfo::new(
+            None,
+            RUST_LONG,
+            None,
+            "Defaults for Rust projects: only react to changes in src/, tests/,\nCargo.toml and Cargo.lock, never target/, filter to .rs and Cargo files\nunless -f is passed, and relativize paths (see README)",
+        ),
+        OptionInfo::new(
+            None,
+            RESOLVE_SYMLINK_TARGETS_LONG,
+            None,
+            "Also watch the targets of symlinks in the tree which point outside\nit (found on startup), reporting changes to them as changes to the links",
+        ),
+        OptionInfo::new(
+            None,
+            WATCH_LIST_LONG,
+            Some("file"),
+            "Watch only the files listed in this file, one per line (relative to\nthe working directory; blank lines and lines starting with # are\nskipped); listed files need not exist yet, but their directories must[5]",
+        ),
+        OptionInfo::new(
+            None,
+            ALSO_WATCH_LONG,
+            Some("path"),
+            "Also watch this existing file or directory, e.g. a Cargo.lock above\nthe watched directory; a file's changes always count, a directory's\nonly those the filters accept (see README)[5]",
+        ),
+        OptionInfo::new(
+            None,
+            EMIT_EMPTY_LONG,
+            None,
+            "Run the command each time the delay elapses after a change, even if\nthe changed paths were already passed to an earlier run - for commands\nwhich only need to know something changed",
+        ),
+        OptionInfo::new(
+            None,
+            MAX_EVENTS_PER_SECOND_LONG,
+            Some("n"),
+            "Drop changes beyond this many in any second, reporting how many were\ndropped to stderr - a safety valve against runaway processes writing files",
+        ),
+        OptionInfo::new(
+            None,
+            WORKING_SET_LIMIT_LONG,
+            Some("n"),
+            "Once this many changed paths are waiting, run the command with them\nimmediately rather than waiting for the delay, bounding memory use;\nchanges made while the command runs still collect until it exits",
+        ),
+        OptionInfo::new(
+            None,
+            MIN_PATHS_LONG,
+            Some("n"),
+            "Only run the command for a batch of at least this many changed paths;\nsmaller ones are kept and run with later changes.  --flush-on,\n--working-set-limit and stdin triggers run whatever is waiting",
+        ),
+        OptionInfo::new(
+            None,
+            DROP_BELOW_MIN_PATHS_LONG,
+            None,
+            "Discard batches smaller than --min-paths instead of keeping them",
+        ),
+        OptionInfo::new(
+            None,
+            ACTIVE_HOURS_LONG,
+            Some("hours"),
+            "Only run the command between these local times of day, e.g.\n09:00-18:00 (or 22:00-06:00, past midnight); changes outside them wait\nfor the window to open.  --flush-on, --working-set-limit and stdin\ntriggers still run",
+        ),
+        OptionInfo::new(
+            None,
+            DROP_OUTSIDE_ACTIVE_HOURS_LONG,
+            None,
+            "Discard batches due outside --active-hours instead of keeping them",
+        ),
+        OptionInfo::new(
+            None,
+            STDIN_FILE_LONG,
+            Some("file"),
+            "Connect this file to the command's stdin, reading it afresh each run;\nignored with --pty",
+        ),
+        OptionInfo::new(
+            None,
+            TAG_STREAMS_LONG,
+            None,
+            "Prefix each line of the command's output with out| or err| (colored\non a terminal unless NO_COLOR is set); ignored with --pty",
+        ),
+        OptionInfo::new(
+            None,
+            TIMESTAMP_OUTPUT_LONG,
+            None,
+            "Prefix each line of the command's output with the time it was read;\nignored with --pty",
+        ),
+        OptionInfo::new(
+            None,
+            TIMESTAMP_FORMAT_LONG,
+            Some("fmt"),
+            format!(
+                "The strftime format of {} timestamps\n(default {})",
+                TIMESTAMP_OUTPUT_LONG, DEFAULT_TIMESTAMP_FORMAT
+            ),
+        ),
+        OptionInfo::new(
+            None,
+            MAX_OUTPUT_BYTES_LONG,
+            Some("n"),
+            "Pass on at most this many bytes of the command's output (stdout and\nstderr together) per run, then print ...(truncated); ignored with --pty",
+        ),
+        OptionInfo::new(
+            None,
+            FLUSH_ON_LONG,
+            Some("file"),
+            "When this file (which need not exist yet) changes, run the command\nimmediately with any pending changes, rather than waiting for the\ndelay - e.g. touch it to build now",
+        ),
+        OptionInfo::new(
+            None,
+            EXIT_ON_LONG,
+            Some("file"),
+            "When this file (which need not exist yet) changes, stop taking changes,\nrun the command with any pending ones, wait for it and exit 0 - as\nSIGUSR2 does on unix",
+        ),
+        OptionInfo::new(
+            None,
+            POLL_FALLBACK_LONG,
+            Some("t"),
+            "Every t, write a probe file (.watchfs-probe-<pid>) to the watched\ndirectory; if the file watcher misses it, or fails, switch to polling\nfor changes every 2s - for container and VM mounts[3]",
+        ),
+        OptionInfo::new(
+            None,
+            POLL_PATH_LONG,
+            Some("dir"),
+            "Also poll this directory beneath the watched one for changes every 2s,\nfor a mount in the tree whose events never arrive (e.g. a bind mount\nin a container), while the rest is watched natively[5]",
+        ),
+        OptionInfo::new(
+            None,
+            MAX_WATCHERS_LONG,
+            Some("n"),
+            "Exit on startup if more than this many directories would be watched,\nrather than exhausting the system's limit on watches part way",
+        ),
+        OptionInfo::new(
+            None,
+            PRINT_WATCHED_PATHS_LONG,
+            None,
+            "On startup, list every directory being watched to stderr - to find\nout why changes to a file are not noticed",
+        ),
+        OptionInfo::new(
+            None,
+            DEBUG_FILTER_LONG,
+            None,
+            "For every event, print to stderr whether it counts as a change and the\nrule which decided - -f, a pattern file, --rust, --watch-list and so on",
+        ),
+        OptionInfo::new(
+            None,
+            DIRS_ONLY_LONG,
+            None,
+            "Only react to changes to directories (e.g. a new directory being\ncreated), not files; deleted directories cannot be detected as such",
+        ),
+        OptionInfo::new(
+            Some(EXIT_ON_ERROR_SHORT),
+            EXIT_ON_ERROR_LONG,
+            None,
+            "Exit if the command returns non-zero",
+        ),
+        OptionInfo::new(
+            None,
+            MAX_FAILURES_LONG,
+            Some("n"),
+            "Exit once the command has failed (or could not be started) n times in\na row; a success starts the count again",
+        ),
+        OptionInfo::new(
+            None,
+            SUCCESS_CODES_LONG,
+            Some("codes"),
+            "Exit codes of the command which count as success, instead of just 0,\nfor -x, -o, --pause-on-error and the other success and failure\noptions, e.g. 0,2[5]",
+        ),
+        OptionInfo::new(
+            Some(ONCE_SHORT),
+            ONCE_LONG,
+            None,
+            "Exit after running the command *successfully* (zero exit, or one of\n--success-codes) once",
+        ),
+        OptionInfo::new(
+            Some(NON_RECURSIVE_SHORT),
+            NON_RECURSIVE_LONG,
+            None,
+            "Do not listen to subdirectories of the target directory, only\nthe target.",
+        ),
+        OptionInfo::new(
+            None,
+            ONE_FILESYSTEM_LONG,
+            None,
+            "Do not watch directories on other filesystems than the target's - mounts\nwithin the tree - or pass on changes to paths on them, like find -xdev\n(unix only)",
+        ),
+        OptionInfo::new(
+            None,
+            OWNER_LONG,
+            Some("user"),
+            "Only pass on changes to files owned by this user, a name or uid -\ndeletions always count, having no owner left to check (unix only)",
+        ),
+        OptionInfo::new(
+            None,
+            PRUNE_DIR_GLOB_LONG,
+            Some("glob"),
+            "Never watch directories matching this glob, e.g. '**/target', or pass on\nchanges beneath them - cheaper than filtering out their changes; may be\nrepeated",
+        ),
+        OptionInfo::new(
+            None,
+            PRESERVE_ORDER_LONG,
+            None,
+            "Pass changed paths in the order they first changed, rather than\nsorted",
+        ),
+        OptionInfo::new(
+            None,
+            FAILURE_COOLDOWN_LONG,
+            Some("t"),
+            "After the command fails, wait until this much time has passed before\nrunning it again[3]",
+        ),
+        OptionInfo::new(
+            None,
+            STDIN_TRIGGER_LONG,
+            None,
+            "Run the command immediately when Enter is pressed (an empty line is\nread from stdin), with whatever changes have accumulated, if any",
+        ),
+        OptionInfo::new(
+            None,
+            JSON_LONG,
+            None,
+            "Print a JSON record of each batch of changes to stdout; if no command\nis passed, nothing else is run",
+        ),
+        OptionInfo::new(
+            None,
+            JSON_PRETTY_LONG,
+            None,
+            "Like --json, but indent each record over several lines for reading;\nrecords sent to --listen clients are unaffected",
+        ),
+        OptionInfo::new(
+            None,
+            LISTEN_LONG,
+            Some("addr"),
+            "Send the --json record of each batch to every client connected to this\nTCP host:port or (on unix) unix domain socket path",
+        ),
+        OptionInfo::new(
+            None,
+            JSON_FILE_LONG,
+            Some("file"),
+            "Append the --json record of each batch to this file, one per line, as\nwell as running the command",
+        ),
+        OptionInfo::new(
+            None,
+            FIFO_LONG,
+            Some("path"),
+            "Write the --json record of each batch to this named pipe (created if\nmissing, on unix), skipping batches while nothing is reading it",
+        ),
+        OptionInfo::new(
+            None,
+            MIRROR_TO_LONG,
+            Some("dir"),
+            "Copy each changed file to the same place under this directory (created if\nmissing), and remove the copies of deleted ones - like rsync, without\nneeding a command",
+        ),
+        OptionInfo::new(
+            None,
+            NO_RUN_LONG,
+            None,
+            "Never run a command - just report changes with --json or --listen",
+        ),
+        OptionInfo::new(
+            None,
+            JSON_SCHEMA_VERSION_LONG,
+            None,
+            "Print the version of the --json record format and exit",
+        ),
+        OptionInfo::new(
+            None,
+            HEARTBEAT_LONG,
+            Some("t"),
+            "Print a line to stderr each time this long passes with no file changes,\nto show watchfs is still alive and how many changes wait for a running\ncommand[3]",
+        ),
+        OptionInfo::new(
+            None,
+            RECORD_LONG,
+            Some("file"),
+            "Append every raw event received, whether or not it is filtered out, to\nthis file as JSON lines - for debugging",
+        ),
+        OptionInfo::new(
+            None,
+            REPLAY_EVENTS_LONG,
+            Some("file"),
+            "Instead of watching, feed the events in a file written by --record\nthrough the same filtering and debouncing, then exit",
+        ),
+        OptionInfo::new(
+            None,
+            REPLAY_SPEED_LONG,
+            Some("x"),
+            "Replay --replay-events events spaced out as they were recorded, this\nmany times as fast - 1 for the original timing; 0, the default, sends\nthem all at once",
+        ),
+        OptionInfo::new(
+            None,
+            STATUS_TO_LONG,
+            Some("dest"),
+            "Where to write watchfs's own messages - stdout, stderr, file:<path>\n(appended to) or null; by default -v detail goes to stdout and the rest\nto stderr.  --json records and the command's output are unaffected",
+        ),
+        OptionInfo::new(
+            None,
+            RUN_LOG_LONG,
+            Some("file"),
+            "Append a JSON record of each run of the command to this file, one per\nline - when it started, how many paths it was run for, its exit code,\nwhether it succeeded and how long it took (see README)",
+        ),
+        OptionInfo::new(
+            Some(VERBOSE_SHORT),
+            VERBOSE_LONG,
+            None,
+            "Describe what the application is doing as it does it; repeat (-vv or\n--verbose=n) to also log warnings, then info, debug and trace detail[2]",
+        ),
+        OptionInfo::new(
+            None,
+            HELP_JSON_LONG,
+            None,
+            "Print a JSON description of the supported options and exit",
+        ),
+        OptionInfo::new(Some(HELP_SHORT), HELP_LONG, None, "Print this help"),
+    ]
+}
+
 fn print_help(err: bool) {
     println(err, format!("{} {}", NAME, VERSION));
 
@@ -456,75 +3841,46 @@ fn print_help(err: bool) {
         PASS_CHANGED_PATHS_SHORT, PASS_CHANGED_PATHS_LONG, SHELL_SHORT, SHELL_LONG, RELATIVIZE_SHORT, RELATIVIZE_LONG,
         ONCE_SHORT, ONCE_LONG, NON_RECURSIVE_SHORT, NON_RECURSIVE_LONG, DIR_SHORT, DIR_LONG, EXIT_ON_ERROR_SHORT, EXIT_ON_ERROR_LONG));
 
-    // println(err, "Usage: watchfs [-v|--verbose] [-h|--help] [-s|--seconds n] [-f|--filter regex]\n              [-p|--pass-changed-paths] [-l|--shell] [-r|--relativize-paths] \n              [-o|--once] [-n|--non-recursive] [-d|dir d] command args...",);
     println(err, "\nWatch a folder for file changes, and run some command after any change,\nonce a timeout has elapsed with no further changes.",);
     println(err, "\nThe trailing portion of the command-line is the command that should be run.  If\nnone is supplied, `echo` will be substituted and paths will be printed to the\nconsole.",);
     println(err, "\nArguments:\n----------");
+    for opt in options() {
+        let mut line = opt.synopsis();
+        // Descriptions start in the fourth tab stop
+        if line.len() >= 24 {
+            line.push_str("\n\t\t\t");
+        } else {
+            for _ in 0..(3 - line.len() / 8) {
+                line.push('\t');
+            }
+        }
+        line.push_str(opt.description.replace('\n', "\n\t\t\t").as_str());
+        println(err, line);
+    }
+    println(err, "\n");
     println(
         err,
-        format!(
-            " {} {} d\t\tThe directory to watch (default {})",
-            DIR_SHORT, DIR_LONG, DEFAULT_PATH
-        ),
-    );
-    println(err, format!(" {} {} n\t\tThe number of seconds to wait for changes to cease before running the\n\t\t\tcommand (default {})", 
-        SECONDS_SHORT, SECONDS_LONG, DEFAULT_DELAY_SECONDS),);
-    println(
-        err,
-        format!(
-            " {} {}\t\tExecute the command in a shell (`sh -c` on unix, `cmd /C` on windows)",
-            SHELL_SHORT, SHELL_LONG
-        ),
-    );
-    println(
-        err,
-        format!(
-            " {} {}\tPass paths to files that changed as arguments to the command",
-            PASS_CHANGED_PATHS_SHORT, PASS_CHANGED_PATHS_LONG
-        ),
-    );
-    println(
-        err,
-        format!(
-            " {} {}\tMake paths to changed files relative to the directory being watched",
-            RELATIVIZE_SHORT, RELATIVIZE_LONG
-        ),
-    );
-    println(err, format!(" {} {} regexp\tOnly notify about file paths that match this regular expression\n\t\t\t(matches against the fully qualified path, regardless of -r)[1]",FILTER_SHORT, FILTER_LONG));
-    println(
-        err,
-        format!(
-            " {} {}\tExit if the command returns non-zero",
-            EXIT_ON_ERROR_SHORT, EXIT_ON_ERROR_LONG
-        ),
+        " [1] - regex syntax supported by https://docs.rs/regex/latest/regex/",
     );
     println(
         err,
-        format!(
-            " {} {}\t\tExit after running the command *successfully* (zero exit) once",
-            ONCE_SHORT, ONCE_LONG
-        ),
+        " [2] - for detailed logging, set the RUST_LOG environment variable to one of info,\n       debug or trace; if set, it takes precedence over the number of -v flags.",
     );
-    println(err, format!(" {} {} n\tDo not listen to subdirectories of the target directory, only\n\t\t\tthe target.", NON_RECURSIVE_SHORT, NON_RECURSIVE_LONG));
     println(
         err,
-        format!(
-            " {} {}\t\tDescribe what the application is doing as it does it[2]",
-            VERBOSE_SHORT, VERBOSE_LONG
-        ),
+        " [3] - durations are a number followed by a unit of ms, s, m or h, e.g. 500ms or 2m;\n       a bare number is a number of seconds.",
     );
     println(
         err,
-        format!(" {} {}\t\tPrint this help\n", HELP_SHORT, HELP_LONG),
+        " [4] - use $$ for a literal $; referencing an undefined variable is an error.",
     );
-    println(err, "");
     println(
         err,
-        " [1] - regex syntax supported by https://docs.rs/regex/latest/regex/",
+        " [5] - may be repeated, or passed several values at once separated by commas or\n       newlines, e.g. a,b; use \\, for a comma within a value.",
     );
     println(
         err,
-        " [2] - for detailed logging, set the RUST_LOG environment variable to one of info,\n       debug or trace.",
+        " [6] - paths with no --on-* command for their kind of change go to the command; a path\n       changed in several ways in one batch goes to one command - deleted if it is now\n       gone, otherwise created if it was created at all, otherwise modified.",
     );
 
     println(err, "\nThe argument interpreter will assume that all arguments including and subsequent\nto the first argument which is not one of the above starts the command to run on changes.");
@@ -534,7 +3890,415 @@ fn print_help(err: bool) {
     println(err, "");
 }
 
-fn print_help_and_exit<A: AsRef<std::ffi::OsStr>>(code: i32, msg: Option<A>) {
+/// Print a JSON description of the options we support, for tools which wrap watchfs
+/// (completions, editor integrations) and should not need to scrape the help text
+fn print_help_json() {
+    let mut result = format!(
+        "{{\"name\":{},\"version\":{},\"options\":[",
+        json_string(NAME),
+        json_string(VERSION)
+    );
+    for (ix, opt) in options().iter().enumerate() {
+        if ix > 0 {
+            result.push(',');
+        }
+        result.push_str(
+            format!(
+                "{{\"short\":{},\"long\":{},\"takes_value\":{},\"value\":{},\"description\":{}}}",
+                opt.short.map(json_string).unwrap_or_else(|| "null".to_string()),
+                json_string(opt.long),
+                opt.value.is_some(),
+                opt.value.map(json_string).unwrap_or_else(|| "null".to_string()),
+                json_string(opt.description.replace('\n', " ").as_str()),
+            )
+            .as_str(),
+        );
+    }
+    result.push_str("]}");
+    println!("{}", result);
+}
+
+/// Quote and escape a string for inclusion in JSON output
+fn json_string(st: &str) -> String {
+    serde_json::to_string(st).expect("Strings are always serializable")
+}
+
+/// Fetch the value following the flag at `i`, advancing `i` past it, or exit with
+/// an error if the flag is the last argument
+fn value_of<'a>(
+    args: &'a [String],
+    i: &mut usize,
+    short: Option<&str>,
+    long: &str,
+    what: &str,
+) -> Result<&'a String, (i32, String)> {
+    if let Some(next) = args.get(*i + 1) {
+        // Skip looking for a flag in the next one - it's our value
+        *i += 1;
+        Ok(next)
+    } else {
+        let flag = match short {
+            Some(short) => format!("{}/{}", short, long),
+            None => long.to_string(),
+        };
+        Err((13, format!("{} must be followed by {}", flag, what)))
+    }
+}
+
+/// Expands `$VAR` and `${VAR}` references in a string, with `$$` producing a literal `$`;
+/// returns the name of the variable if one is undefined
+pub(crate) fn expand_env(st: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(st.len());
+    let mut chars = st.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            result.push(c);
+            continue;
+        }
+        let name: String = match chars.peek() {
+            Some('$') => {
+                chars.next();
+                result.push('$');
+                continue;
+            }
+            Some('{') => {
+                chars.next();
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(format!("${{{}", name)),
+                    }
+                }
+                name
+            }
+            _ => {
+                let mut name = String::new();
+                while let Some(c) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || *c == '_' {
+                        name.push(*c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                name
+            }
+        };
+        if name.is_empty() {
+            // A lone $ - leave it alone
+            result.push('$');
+            continue;
+        }
+        match std::env::var(&name) {
+            Ok(value) => result.push_str(value.as_str()),
+            Err(_) => return Err(name),
+        }
+    }
+    Ok(result)
+}
+
+/// Expand a string as `expand_env` does, with an exit code and message if a variable it
+/// references is not set
+fn checked_expand_env(st: &str) -> Result<String, (i32, String)> {
+    expand_env(st).map_err(|name| {
+        (
+            16,
+            format!(
+                "Environment variable '{}' referenced in '{}' is not set",
+                name, st
+            ),
+        )
+    })
+}
+
+/// Read the contents of a file named by an option
+fn read_file(path: &str, long: &str) -> Result<String, (i32, String)> {
+    fs::read_to_string(path).map_err(|e| {
+        (
+            15,
+            format!("Could not read {} file '{}': {}", long, path, e),
+        )
+    })
+}
+
+/// The value of a result from parsing arguments, exiting with its exit code and message if
+/// parsing failed
+fn or_exit<T>(result: Result<T, (i32, String)>) -> T {
+    match result {
+        Ok(value) => value,
+        Err((code, message)) => print_help_and_exit(code, Some(message)),
+    }
+}
+
+/// Open the destination for status messages passed to --status-to, exiting if it is invalid
+fn status_sink(to: &str) -> status::Sink {
+    match to {
+        "stdout" => status::Sink::Stdout,
+        "stderr" => status::Sink::Stderr,
+        "null" => status::Sink::Null,
+        file if file.starts_with(STATUS_FILE_PREFIX) => {
+            let path = &file[STATUS_FILE_PREFIX.len()..];
+            match fs::OpenOptions::new().create(true).append(true).open(path) {
+                Ok(f) => status::Sink::File(std::sync::Mutex::new(f)),
+                Err(e) => print_help_and_exit(
+                    15,
+                    Some(format!("Could not open {} file '{}': {}", STATUS_TO_LONG, path, e)),
+                ),
+            }
+        }
+        other => print_help_and_exit(
+            14,
+            Some(format!(
+                "{} must be stdout, stderr, {}<path> or null, not '{}'",
+                STATUS_TO_LONG, STATUS_FILE_PREFIX, other
+            )),
+        ),
+    }
+}
+
+/// The directory to watch with --no-canonicalize - the -d path, or the working directory, made
+/// absolute without resolving any symlinks in it
+fn literal_dir(given: Option<&str>) -> PathBuf {
+    let given = match given {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            // The shell's idea of the working directory keeps the symlinks getcwd() resolves
+            let pwd = std::env::var_os("PWD").map(PathBuf::from);
+            match pwd {
+                Some(pwd)
+                    if pwd.is_absolute()
+                        && fs::canonicalize(&pwd).ok() == fs::canonicalize(DEFAULT_PATH).ok() =>
+                {
+                    pwd
+                }
+                _ => PathBuf::from(DEFAULT_PATH),
+            }
+        }
+    };
+    let absolute = if given.is_absolute() {
+        given
+    } else {
+        match std::env::current_dir() {
+            Ok(dir) => dir.join(given),
+            Err(e) => print_help_and_exit(6, Some(format!("No working directory? {:?}", e))),
+        }
+    };
+    // Drop any . components, but not .. ones, which may follow a symlink
+    absolute
+        .components()
+        .filter(|part| *part != std::path::Component::CurDir)
+        .collect()
+}
+
+/// The id of the device a file or directory (not what a symlink points to) is on
+#[cfg(unix)]
+pub(crate) fn device_of(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::symlink_metadata(path).ok().map(|meta| meta.dev())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn device_of(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// The user id owning a file or directory (not what a symlink points to)
+#[cfg(unix)]
+fn owner_of(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
+    fs::symlink_metadata(path).ok().map(|meta| meta.uid())
+}
+
+#[cfg(not(unix))]
+fn owner_of(_path: &Path) -> Option<u32> {
+    None
+}
+
+/// The user id of a user given by uid or name for --owner, if there is such a user
+#[cfg(unix)]
+fn user_id(user: &str) -> Option<u32> {
+    if let Ok(uid) = user.parse() {
+        return Some(uid);
+    }
+    let name = std::ffi::CString::new(user).ok()?;
+    // Safe - getpwnam_r only writes to the buffers passed to it, which outlive the call
+    unsafe {
+        let mut entry: libc::passwd = std::mem::zeroed();
+        let mut buf = vec![0 as libc::c_char; 16384];
+        let mut found = std::ptr::null_mut();
+        let code = libc::getpwnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        );
+        (code == 0 && !found.is_null()).then_some(entry.pw_uid)
+    }
+}
+
+#[cfg(not(unix))]
+fn user_id(_user: &str) -> Option<u32> {
+    None
+}
+
+/// Resolve a path to a file which may not exist yet the way the watcher will report it - with
+/// its parent directory canonicalized - failing if the parent does not exist
+fn absolute_file_path(path: &str, long: &str) -> Result<PathBuf, (i32, String)> {
+    let path = PathBuf::from(path);
+    let (parent, name) = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => (parent, name),
+        (_, Some(name)) => (Path::new("."), name),
+        _ => return Err((14, format!("{} needs a file: {:?}", long, path))),
+    };
+    match fs::canonicalize(parent) {
+        Ok(dir) => Ok(dir.join(name)),
+        Err(e) => Err((
+            14,
+            format!(
+                "Directory of {} file {:?} cannot be found: {}",
+                long, path, e
+            ),
+        )),
+    }
+}
+
+/// Fetch the value following the flag at `i` for an option which may be repeated, split into
+/// the values separated by commas or newlines in it; `\,` is a literal comma
+fn list_value_of(
+    args: &[String],
+    i: &mut usize,
+    long: &str,
+    what: &str,
+) -> Result<Vec<String>, (i32, String)> {
+    Ok(split_list(value_of(args, i, None, long, what)?))
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut current = String::new();
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&LIST_DELIMITER) => {
+                current.push(LIST_DELIMITER);
+                chars.next();
+            }
+            LIST_DELIMITER | '\n' => {
+                if !current.trim().is_empty() {
+                    result.push(std::mem::take(&mut current));
+                }
+                current.clear();
+            }
+            _ => current.push(c),
+        }
+    }
+    if !current.trim().is_empty() {
+        result.push(current);
+    }
+    result
+}
+
+/// Parse a -s/--seconds delay from `what`, failing if it is not an integer > 0
+fn parse_seconds(secs: &str, what: &str) -> Result<usize, (i32, String)> {
+    match secs.parse() {
+        Ok(0) => Err((7, format!("Delay must be > 0 in {}", what))),
+        Ok(seconds) if seconds > MAX_DURATION_SECONDS as usize => Err((
+            7,
+            format!(
+                "Delay must be at most {} seconds in {}",
+                MAX_DURATION_SECONDS, what
+            ),
+        )),
+        Ok(seconds) => Ok(seconds),
+        Err(_) => Err((
+            2,
+            format!("Could not parse {} string '{}' as an integer", what, secs),
+        )),
+    }
+}
+
+/// Fetch and parse the positive integer following the flag at `i`, failing if it is missing,
+/// unparseable or zero
+fn count_value_of(args: &[String], i: &mut usize, long: &str) -> Result<usize, (i32, String)> {
+    let value = value_of(args, i, None, long, "a number")?;
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err((
+            14,
+            format!("{} must be a number > 0, not '{}'", long, value),
+        )),
+    }
+}
+
+/// Parse a signal name such as SIGTERM or TERM, in any case
+fn signal_value_of(args: &[String], i: &mut usize, long: &str) -> Result<i32, (i32, String)> {
+    let value = value_of(args, i, None, long, "a signal name")?;
+    let upper = value.to_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    match cleanup::SIGNALS.iter().find(|(n, _)| *n == name) {
+        Some((_, signal)) => Ok(*signal),
+        None => Err((
+            14,
+            format!(
+                "Unknown signal '{}' for {} - must be one of {}",
+                value,
+                long,
+                cleanup::SIGNALS
+                    .iter()
+                    .map(|(n, _)| format!("SIG{}", n))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        )),
+    }
+}
+
+/// Fetch and parse the duration following the flag at `i`, failing if it is missing,
+/// unparseable or zero
+fn duration_value_of(
+    args: &[String],
+    i: &mut usize,
+    short: Option<&str>,
+    long: &str,
+) -> Result<Duration, (i32, String)> {
+    let value = value_of(args, i, short, long, "a duration")?;
+    match parse_duration(value) {
+        Some(duration) if duration <= Duration::zero() => {
+            Err((14, format!("{} must be > 0", long)))
+        }
+        Some(duration) => Ok(duration),
+        None => Err((
+            14,
+            format!(
+                "Could not parse '{}' as a duration for {} - use, e.g., 500ms, 30s, 5m or 1h, of up to {} seconds",
+                value, long, MAX_DURATION_SECONDS
+            ),
+        )),
+    }
+}
+
+/// Parses a duration such as `500ms`, `30s`, `5m` or `1h`; a bare number is seconds.  Ones
+/// longer than `MAX_DURATION_SECONDS` are rejected like any other unparseable value
+pub(crate) fn parse_duration(st: &str) -> Option<Duration> {
+    let st = st.trim();
+    let split = st.find(|c: char| !c.is_ascii_digit()).unwrap_or(st.len());
+    let (digits, unit) = st.split_at(split);
+    let amount: i64 = digits.parse().ok()?;
+    let unit_millis = match unit.trim() {
+        "ms" => 1,
+        "" | "s" => 1_000,
+        "m" => 60_000,
+        "h" => 3_600_000,
+        _ => return None,
+    };
+    let millis = amount.checked_mul(unit_millis)?;
+    (millis <= MAX_DURATION_SECONDS * 1_000).then(|| Duration::milliseconds(millis))
+}
+
+fn print_help_and_exit<A: AsRef<std::ffi::OsStr>>(code: i32, msg: Option<A>) -> ! {
     if let Some(m) = msg {
         println(code != 0, "------------- WatchFS Error -------------");
         println(code != 0, "");
@@ -545,3 +4309,203 @@ fn print_help_and_exit<A: AsRef<std::ffi::OsStr>>(code: i32, msg: Option<A>) {
     print_help(code != 0);
     std::process::exit(code);
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// An empty scratch directory for one test, by its real path
+    pub(crate) fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("watchfs-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::canonicalize(dir).unwrap()
+    }
+
+    /// Arguments parsed from command-line flags, with the watched directory and pattern files
+    /// settled as `finish` would leave them
+    pub(crate) fn parsed(flags: &[&str]) -> Args {
+        let cli: Vec<String> = std::iter::once("watchfs")
+            .chain(flags.iter().copied())
+            .map(String::from)
+            .collect();
+        let mut args = Args::parse(&cli).unwrap();
+        args.locate_dir().unwrap();
+        args.compile_patterns().unwrap();
+        args
+    }
+
+    /// The default arguments for watching `dir`, with these --include-from and --exclude-from
+    /// patterns written to files in it
+    fn with_patterns(dir: &Path, include: &str, exclude: &str) -> Args {
+        let (include_from, exclude_from) = (dir.join("include"), dir.join("exclude"));
+        fs::write(&include_from, include).unwrap();
+        fs::write(&exclude_from, exclude).unwrap();
+        let mut args = Args {
+            path: dir.to_string_lossy().to_string(),
+            include_from: vec![include_from.to_string_lossy().to_string()],
+            exclude_from: vec![exclude_from.to_string_lossy().to_string()],
+            ..Args::default()
+        };
+        args.compile_patterns().unwrap();
+        args
+    }
+
+    /// Arguments parsed from command-line flags
+    fn cli(flags: &[&str]) -> Args {
+        let cli: Vec<String> = std::iter::once("watchfs")
+            .chain(flags.iter().copied())
+            .map(String::from)
+            .collect();
+        Args::parse(&cli).unwrap()
+    }
+
+    /// The default arguments, with these -f regexes
+    fn filtered(filters: &[&str]) -> Args {
+        Args {
+            filter: filters.iter().map(|rex| Regex::new(rex).unwrap()).collect(),
+            ..Args::default()
+        }
+    }
+
+    #[test]
+    fn stacked_v_flags_raise_verbosity() {
+        assert_eq!(1, cli(&["-v", "make"]).verbosity);
+        assert_eq!(3, cli(&["-vvv", "make"]).verbosity);
+        assert_eq!(3, cli(&["-v", "-vv", "make"]).verbosity);
+    }
+
+    #[test]
+    fn command_words_are_not_verbosity() {
+        for word in ["ébuild", "xvv", "avv"] {
+            let args = cli(&["-d", "/tmp", word, "x"]);
+            assert_eq!(vec![word, "x"], args.command);
+            assert_eq!(0, args.verbosity);
+        }
+    }
+
+    #[test]
+    fn rescans_are_never_changes() {
+        let args = Args {
+            chmod_files: true,
+            chmod_dirs: true,
+            ..Args::default()
+        };
+        assert!(!args.should_handle(Path::new("/work/a.rs"), Op::RESCAN));
+        assert!(args.should_handle(Path::new("/work/a.rs"), Op::WRITE));
+    }
+
+    #[test]
+    fn chmod_events_count_only_for_the_kinds_asked_for() {
+        let dir = scratch("chmod");
+        let (file, sub) = (dir.join("a.rs"), dir.join("src"));
+        fs::write(&file, "").unwrap();
+        fs::create_dir(&sub).unwrap();
+        let counted = |chmod_files, chmod_dirs| {
+            let args = Args {
+                chmod_files,
+                chmod_dirs,
+                ..Args::default()
+            };
+            (
+                args.should_handle(&file, Op::CHMOD),
+                args.should_handle(&sub, Op::CHMOD),
+            )
+        };
+        assert_eq!((false, false), counted(false, false));
+        assert_eq!((true, false), counted(true, false));
+        assert_eq!((false, true), counted(false, true));
+        assert_eq!((true, true), counted(true, true));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn filter_matches_the_whole_path() {
+        let args = filtered(&[r"\.rs$"]);
+        assert!(args.should_handle(Path::new("/work/src/main.rs"), Op::WRITE));
+        assert!(args.should_handle(Path::new("/work/src/main.rs"), Op::CREATE));
+        assert!(!args.should_handle(Path::new("/work/README.md"), Op::WRITE));
+        let args = filtered(&["^/work/src/"]);
+        assert!(args.should_handle(Path::new("/work/src/main.rs"), Op::WRITE));
+        assert!(!args.should_handle(Path::new("/work/tests/src/a.rs"), Op::WRITE));
+    }
+
+    #[test]
+    fn any_repeated_filter_may_match() {
+        let args = filtered(&[r"\.rs$", r"\.toml$"]);
+        assert!(args.should_handle(Path::new("/work/src/main.rs"), Op::WRITE));
+        assert!(args.should_handle(Path::new("/work/Cargo.toml"), Op::WRITE));
+        assert!(!args.should_handle(Path::new("/work/README.md"), Op::WRITE));
+    }
+
+    #[test]
+    fn filter_basename_matches_only_the_file_name() {
+        let path = Path::new("/work/src/main.rs");
+        assert!(!filtered(&[r"^main\.rs$"]).should_handle(path, Op::WRITE));
+        let args = Args {
+            filter_basename: true,
+            ..filtered(&[r"^main\.rs$"])
+        };
+        assert!(args.should_handle(path, Op::WRITE));
+        assert!(!args.should_handle(Path::new("/work/src/main.bak"), Op::WRITE));
+    }
+
+    #[test]
+    fn exclude_globs_win_over_include_globs() {
+        let dir = scratch("patterns");
+        let args = with_patterns(&dir, "*.rs\n", "target/\n");
+        assert!(args.should_handle(&dir.join("src/main.rs"), Op::WRITE));
+        assert!(!args.should_handle(&dir.join("target/a.rs"), Op::WRITE));
+        assert!(!args.should_handle(&dir.join("README.md"), Op::WRITE));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn filter_component_matches_whole_components() {
+        let args = Args {
+            path: String::from("/work"),
+            filter_component: true,
+            ..filtered(&["^src$"])
+        };
+        assert!(args.should_handle(Path::new("/work/src/main.rs"), Op::WRITE));
+        assert!(!args.should_handle(Path::new("/work/mysrc/main.rs"), Op::WRITE));
+        assert!(args.should_handle(Path::new("/work/a/src"), Op::WRITE));
+    }
+
+    #[test]
+    fn hidden_files_are_filtered_like_any_other() {
+        let (hidden, in_hidden) = (Path::new("/work/.env"), Path::new("/work/.git/config"));
+        assert!(filtered(&[]).should_handle(hidden, Op::WRITE));
+        assert!(filtered(&[]).should_handle(in_hidden, Op::WRITE));
+        let args = filtered(&[r"/\.[^/]*$"]);
+        assert!(args.should_handle(hidden, Op::WRITE));
+        assert!(!args.should_handle(in_hidden, Op::WRITE));
+        let dir = scratch("hidden");
+        let args = with_patterns(&dir, "", ".*\n");
+        assert!(!args.should_handle(&dir.join(".env"), Op::WRITE));
+        assert!(!args.should_handle(&dir.join("src/.main.rs.swp"), Op::WRITE));
+        assert!(args.should_handle(&dir.join("src/main.rs"), Op::WRITE));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cmd_quoting_escapes_percent_signs() {
+        assert_eq!("a^%PATH^%b", quote_for_cmd("a%PATH%b"));
+        assert_eq!(r#""a "^%"PATH"^%"""#, quote_for_cmd("a %PATH%"));
+    }
+
+    #[test]
+    fn cmd_quoting_doubles_quotes() {
+        assert_eq!(r#""say ""hi""""#, quote_for_cmd(r#"say "hi""#));
+        assert_eq!(r#""""""#, quote_for_cmd(r#"""#));
+    }
+
+    #[test]
+    fn cmd_quoting_quotes_only_when_needed() {
+        assert_eq!("plain", quote_for_cmd("plain"));
+        assert_eq!(r#""""#, quote_for_cmd(""));
+        assert_eq!(r#""a b""#, quote_for_cmd("a b"));
+        assert_eq!(r#""a&b""#, quote_for_cmd("a&b"));
+    }
+}
@@ -0,0 +1,167 @@
+//! Centralized teardown of temporary resources (manifests, pidfiles, fifos and the like),
+//! and child processes, that must not be left behind when watchfs exits - whether normally, via one of
+//! the many `exit()` paths (which do not run destructors), or on a signal.
+use log::{debug, error};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Resources to remove on exit, in registration order
+static RESOURCES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Process ids of running children to terminate on exit (with --kill-children-on-exit); on
+/// unix each is the leader of its own process group, and the whole group is signalled
+static CHILDREN: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// RAII guard which removes any registered resources when dropped; created once in `main`,
+/// which also installs the signal handlers that run the same cleanup
+pub(crate) struct Cleanup {
+    _private: (),
+}
+
+impl Cleanup {
+    pub fn install() -> Self {
+        install_signal_handlers();
+        Self { _private: () }
+    }
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        run();
+    }
+}
+
+/// Register a file or directory to be removed when watchfs exits
+pub(crate) fn register<P: AsRef<Path>>(path: P) {
+    debug!("Register for cleanup: {:?}", path.as_ref());
+    RESOURCES.lock().unwrap().push(path.as_ref().to_path_buf());
+}
+
+/// Stop tracking a resource which has been removed (or handed off) by its owner
+pub(crate) fn unregister<P: AsRef<Path>>(path: P) {
+    RESOURCES
+        .lock()
+        .unwrap()
+        .retain(|p| p.as_path() != path.as_ref());
+}
+
+/// Register a running child process to be terminated when watchfs exits
+pub(crate) fn register_child(pid: u32) {
+    debug!("Register child {} for termination on exit", pid);
+    CHILDREN.lock().unwrap().push(pid);
+}
+
+/// Stop tracking a child process which has exited
+pub(crate) fn unregister_child(pid: u32) {
+    CHILDREN.lock().unwrap().retain(|p| *p != pid);
+}
+
+/// Terminate registered children and remove all registered resources; safe to call more
+/// than once
+pub(crate) fn run() {
+    let children = std::mem::take(&mut *CHILDREN.lock().unwrap_or_else(|e| e.into_inner()));
+    for pid in children {
+        debug!("Terminate child {}", pid);
+        terminate(pid);
+    }
+    let resources = std::mem::take(&mut *RESOURCES.lock().unwrap_or_else(|e| e.into_inner()));
+    // Remove in reverse order, so things created inside a registered directory go first
+    for path in resources.iter().rev() {
+        let result = if path.is_dir() {
+            std::fs::remove_dir_all(path)
+        } else if path.exists() {
+            std::fs::remove_file(path)
+        } else {
+            Ok(())
+        };
+        match result {
+            Ok(_) => debug!("Cleaned up {:?}", path),
+            Err(e) => error!("Could not clean up {:?}: {}", path, e),
+        }
+    }
+}
+
+/// Clean up and exit the process - use this rather than `std::process::exit` anywhere
+/// resources may have been registered
+pub(crate) fn exit(code: i32) -> ! {
+    run();
+    std::process::exit(code);
+}
+
+/// Signals which may be sent to stop a child (`--stop-signal`), by name without the `SIG`
+#[cfg(unix)]
+pub(crate) const SIGNALS: &[(&str, i32)] = &[
+    ("TERM", libc::SIGTERM),
+    ("INT", libc::SIGINT),
+    ("HUP", libc::SIGHUP),
+    ("QUIT", libc::SIGQUIT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+];
+
+/// Signals which may be sent to stop a child - accepted for portability of scripts, but any
+/// of them forcibly terminates it
+#[cfg(not(unix))]
+pub(crate) const SIGNALS: &[(&str, i32)] = &[
+    ("TERM", 15),
+    ("INT", 2),
+    ("HUP", 1),
+    ("QUIT", 3),
+    ("KILL", 9),
+    ("USR1", 10),
+    ("USR2", 12),
+];
+
+/// The number of SIGKILL, which cannot be caught - the same on every platform
+pub(crate) const KILL: i32 = 9;
+
+fn terminate(pid: u32) {
+    // SIGTERM
+    signal(pid, SIGNALS[0].1);
+}
+
+/// Send a signal to a child which leads its own process group, and everything in the group
+#[cfg(unix)]
+pub(crate) fn signal(pid: u32, signal: i32) {
+    // Negative pid signals the process group
+    if unsafe { libc::kill(-(pid as libc::pid_t), signal) } != 0 {
+        error!(
+            "Could not signal child {}: {}",
+            pid,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Terminate a child and its descendants - there are no signals to send
+#[cfg(not(unix))]
+pub(crate) fn signal(pid: u32, _signal: i32) {
+    // /T takes down the child's descendants as well
+    let result = std::process::Command::new("taskkill")
+        .args(["/PID", pid.to_string().as_str(), "/T", "/F"])
+        .output();
+    if let Err(e) = result {
+        error!("Could not terminate child {}: {}", pid, e);
+    }
+}
+
+#[cfg(unix)]
+fn install_signal_handlers() {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+    match Signals::new([SIGINT, SIGTERM, SIGHUP]) {
+        Ok(mut signals) => {
+            std::thread::spawn(move || {
+                if let Some(signal) = signals.forever().next() {
+                    debug!("Received signal {} - cleaning up and exiting", signal);
+                    exit(128 + signal);
+                }
+            });
+        }
+        Err(e) => error!("Could not install signal handlers: {}", e),
+    }
+}
+
+#[cfg(not(unix))]
+fn install_signal_handlers() {}
@@ -0,0 +1,122 @@
+//! Generates shell completion scripts from the options described in `args`
+use crate::args::{options, OptionInfo};
+
+/// The shells we can generate completions for
+pub(crate) const SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+/// Returns the completion script for the named shell, if it is one we support
+pub(crate) fn script_for(shell: &str) -> Option<String> {
+    let opts = options();
+    match shell {
+        "bash" => Some(bash(&opts)),
+        "zsh" => Some(zsh(&opts)),
+        "fish" => Some(fish(&opts)),
+        _ => None,
+    }
+}
+
+/// An option's description on a single line, minus any footnote markers like `[1]`
+fn summary(opt: &OptionInfo) -> String {
+    let mut result = opt.description.replace('\n', " ");
+    while let Some(start) = result.rfind('[') {
+        if result[start..].ends_with(']') {
+            result.truncate(start);
+        } else {
+            break;
+        }
+    }
+    result.trim_end().to_string()
+}
+
+/// All names an option can be invoked by
+fn names(opt: &OptionInfo) -> Vec<&'static str> {
+    let mut result = Vec::with_capacity(2);
+    if let Some(short) = opt.short {
+        result.push(short);
+    }
+    result.push(opt.long);
+    result
+}
+
+fn bash(opts: &[OptionInfo]) -> String {
+    let mut all = Vec::new();
+    let mut with_values = Vec::new();
+    for opt in opts {
+        all.extend(names(opt));
+        if opt.value.is_some() {
+            with_values.extend(names(opt));
+        }
+    }
+    let mut result = String::from("# bash completion for watchfs\n_watchfs() {\n");
+    result.push_str("    local cur prev\n");
+    result.push_str("    cur=\"${COMP_WORDS[COMP_CWORD]}\"\n");
+    result.push_str("    prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n");
+    result.push_str("    case \"$prev\" in\n");
+    if !with_values.is_empty() {
+        result.push_str(format!("        {})\n", with_values.join("|")).as_str());
+        result.push_str("            COMPREPLY=( $(compgen -f -- \"$cur\") )\n");
+        result.push_str("            return 0\n");
+        result.push_str("            ;;\n");
+    }
+    result.push_str("    esac\n");
+    result.push_str("    if [[ \"$cur\" == -* ]]; then\n");
+    result.push_str(
+        format!(
+            "        COMPREPLY=( $(compgen -W \"{}\" -- \"$cur\") )\n",
+            all.join(" ")
+        )
+        .as_str(),
+    );
+    result.push_str("        return 0\n");
+    result.push_str("    fi\n");
+    result.push_str("    COMPREPLY=( $(compgen -c -- \"$cur\") )\n");
+    result.push_str("}\ncomplete -F _watchfs watchfs\n");
+    result
+}
+
+fn zsh(opts: &[OptionInfo]) -> String {
+    let mut result = String::from("#compdef watchfs\n\n_arguments -s \\\n");
+    for opt in opts {
+        let desc = summary(opt)
+            .replace('\'', "'\\''")
+            .replace('[', "\\[")
+            .replace(']', "\\]");
+        let value = match opt.value {
+            Some(v) => format!(":{}:_files", v),
+            None => String::new(),
+        };
+        let line = match opt.short {
+            Some(short) => format!(
+                "  '({} {})'{{{},{}}}'[{}]{}' \\\n",
+                short, opt.long, short, opt.long, desc, value
+            ),
+            None => format!("  '{}[{}]{}' \\\n", opt.long, desc, value),
+        };
+        result.push_str(line.as_str());
+    }
+    result.push_str("  '*::command:_normal'\n");
+    result
+}
+
+fn fish(opts: &[OptionInfo]) -> String {
+    let mut result = String::from("# fish completion for watchfs\n");
+    for opt in opts {
+        let mut line = String::from("complete -c watchfs");
+        if let Some(short) = opt.short {
+            line.push_str(format!(" -s {}", short.trim_start_matches('-')).as_str());
+        }
+        line.push_str(format!(" -l {}", opt.long.trim_start_matches('-')).as_str());
+        if opt.value.is_some() {
+            line.push_str(" -r");
+        }
+        line.push_str(
+            format!(
+                " -d '{}'\n",
+                summary(opt).replace('\\', "\\\\").replace('\'', "\\'")
+            )
+            .as_str(),
+        );
+        result.push_str(line.as_str());
+    }
+    result
+}
@@ -0,0 +1,285 @@
+//! Project configuration files (`--config`, or a `.watchfs.toml` found by searching upward
+//! from the working directory), which supply default arguments.
+//!
+//! Keys are the long names of command-line options without the leading `--`; options which
+//! take no value are set with a boolean, others with a string or number, or an array of them
+//! for options which may be repeated.  The `command` key is the command to run, as a string
+//! or an array of arguments.  For example:
+//!
+//! ```toml
+//! dir = "src"
+//! seconds = 2
+//! filter = ['\.rs$', 'Cargo\.toml$']
+//! relativize = true
+//! command = ["cargo", "test"]
+//! ```
+//!
+//! A relative `dir` is resolved against the directory containing the file; with no `dir`,
+//! that directory is the one watched.
+//!
+//! To run several watchers in one process - say, one per part of a monorepo - each
+//! `[[rule]]` table is a set of keys applied after those at the top of the file, which then
+//! serve as defaults for every rule:
+//!
+//! ```toml
+//! seconds = 1
+//!
+//! [[rule]]
+//! dir = "frontend"
+//! command = ["npm", "run", "build"]
+//!
+//! [[rule]]
+//! dir = "backend"
+//! filter = ['\.rs$']
+//! command = ["cargo", "build"]
+//! ```
+//!
+//! Options which affect the whole process - where status and the run log go, reading stdin,
+//! replaying events and exiting - can only be set at the top.
+//!
+//! `--init` writes a template listing every option, commented out, to start from.
+use crate::args::{options, OptionInfo};
+use log::debug;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// The name of the project file searched for
+const FILE_NAME: &str = ".watchfs.toml";
+
+/// Key for the command to run, which is not an option
+const COMMAND_KEY: &str = "command";
+/// Key for the watched directory, which has relative paths resolved against the file's
+const DIR_KEY: &str = "dir";
+/// Key for the array of tables which each describe a watcher of their own
+const RULE_KEY: &str = "rule";
+
+/// Options which apply to the whole process rather than one watcher, so cannot be set in a
+/// `[[rule]]` table
+const PROCESS_WIDE: [&str; 10] = [
+    "--status-to",
+    "--run-log",
+    "--stdin-trigger",
+    "--confirm",
+    "--pause-on-error",
+    "--replay-events",
+    "--once",
+    "--exit-on-error",
+    "--max-failures",
+    "--exit-on",
+];
+
+/// Options left out of the `--init` template, which do nothing useful in a file
+const NOT_CONFIGURABLE: [&str; 6] = [
+    "--config",
+    "--init",
+    "--force",
+    "--help",
+    "--help-json",
+    "--output-json-schema-version",
+];
+
+const TEMPLATE_HEADER: &str = "\
+# Configuration for watchfs, written by `watchfs --init`.
+#
+# Keys are the long names of options without the leading `--`.  Uncomment a line to set it:
+# options which take no value are true or false, others a string or number, or an array of
+# them for options which may be repeated.  Options passed on the command-line override the
+# values here; those left commented out keep their defaults, which are given in their
+# descriptions where there is one.
+
+# The command to run on changes, as a string or an array of arguments, if none is passed
+# command = [\"echo\"]
+";
+
+/// Arguments loaded from a configuration file
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    /// Flags and their values, as they would appear on the command-line
+    pub flags: Vec<String>,
+    /// The command to run if none is passed on the command-line
+    pub command: Vec<String>,
+    /// The `[[rule]]` tables, each with the flags and command it adds to those above
+    pub rules: Vec<Config>,
+}
+
+/// Walk upward from the working directory looking for a `.watchfs.toml`, stopping at the
+/// filesystem root or after checking a directory containing `.git`
+pub(crate) fn discover() -> Option<PathBuf> {
+    let mut dir = std::env::current_dir().ok()?;
+    loop {
+        let candidate = dir.join(FILE_NAME);
+        if candidate.is_file() {
+            debug!("Found project file {:?}", candidate);
+            return Some(candidate);
+        }
+        if dir.join(".git").exists() || !dir.pop() {
+            debug!("No {} found, stopped at {:?}", FILE_NAME, dir);
+            return None;
+        }
+    }
+}
+
+/// Convert the text of a configuration file at `path` into command-line arguments
+pub(crate) fn parse(text: &str, path: &Path) -> Result<Config, String> {
+    let table: toml::Table = text.parse().map_err(|e| format!("{}", e))?;
+    let base = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."));
+    let mut result = Config::default();
+    if !table.contains_key(DIR_KEY) {
+        result.flags.push(format!("--{}", DIR_KEY));
+        result.flags.push(base.to_string_lossy().to_string());
+    }
+    add_table(&table, &base, &mut result, true)?;
+    if let Some(rules) = table.get(RULE_KEY) {
+        let not_tables = || format!("'{0}' must be an array of tables, as [[{0}]]", RULE_KEY);
+        let Value::Array(rules) = rules else {
+            return Err(not_tables());
+        };
+        for (ix, rule) in rules.iter().enumerate() {
+            let Value::Table(rule) = rule else {
+                return Err(not_tables());
+            };
+            let mut config = Config::default();
+            add_table(rule, &base, &mut config, false)
+                .map_err(|e| format!("in [[{}]] {}: {}", RULE_KEY, ix + 1, e))?;
+            result.rules.push(config);
+        }
+    }
+    debug!("Arguments from {:?}: {:?}", path, result);
+    Ok(result)
+}
+
+/// Add the flags and command for the keys of a table to `result` - other than the `[[rule]]`
+/// tables, which are only allowed at the top of the file
+fn add_table(
+    table: &toml::Table,
+    base: &Path,
+    result: &mut Config,
+    top: bool,
+) -> Result<(), String> {
+    let opts = options();
+    for (key, value) in table {
+        if key == RULE_KEY {
+            if top {
+                continue;
+            }
+            return Err(format!("[[{}]] tables cannot be nested", RULE_KEY));
+        }
+        if key == COMMAND_KEY {
+            result.command = match value {
+                Value::String(s) => vec![s.clone()],
+                Value::Array(items) => items
+                    .iter()
+                    .map(|v| scalar(key, v))
+                    .collect::<Result<_, _>>()?,
+                _ => return Err(format!("'{}' must be a string or array", key)),
+            };
+            continue;
+        }
+        let flag = format!("--{}", key);
+        let opt = opts
+            .iter()
+            .find(|o| o.long == flag && key != "config")
+            .ok_or_else(|| format!("Unknown key '{}'", key))?;
+        if !top && PROCESS_WIDE.contains(&opt.long) {
+            return Err(format!(
+                "'{}' applies to every rule, so can only be set at the top of the file",
+                key
+            ));
+        }
+        match (opt.value, value) {
+            (None, Value::Boolean(true)) => result.flags.push(flag),
+            (None, Value::Boolean(false)) => {}
+            (None, _) => return Err(format!("'{}' must be true or false", key)),
+            (Some(_), Value::Array(items)) => {
+                for item in items {
+                    result.flags.push(flag.clone());
+                    result.flags.push(scalar(key, item)?);
+                }
+            }
+            (Some(_), v) => {
+                let mut val = scalar(key, v)?;
+                if key == DIR_KEY && Path::new(&val).is_relative() {
+                    val = base.join(val).to_string_lossy().to_string();
+                }
+                result.flags.push(flag);
+                result.flags.push(val);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn scalar(key: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(n) => Ok(n.to_string()),
+        Value::Float(n) => Ok(n.to_string()),
+        _ => Err(format!("'{}' must be a string or number", key)),
+    }
+}
+
+/// Write the configuration file template to the working directory, unless a file is already
+/// there and `force` is not set
+pub(crate) fn init(force: bool) -> Result<PathBuf, String> {
+    let path = PathBuf::from(FILE_NAME);
+    if path.exists() && !force {
+        return Err(format!("{} already exists - pass --force to replace it", FILE_NAME));
+    }
+    std::fs::write(&path, template()).map_err(|e| format!("Could not write {}: {}", FILE_NAME, e))?;
+    Ok(path)
+}
+
+/// Every option which can be set in a file, described and commented out
+fn template() -> String {
+    let mut result = String::from(TEMPLATE_HEADER);
+    for opt in options() {
+        if NOT_CONFIGURABLE.contains(&opt.long) {
+            continue;
+        }
+        result.push('\n');
+        for line in description(&opt).lines() {
+            result.push_str("# ");
+            result.push_str(line);
+            result.push('\n');
+        }
+        let key = opt.long.trim_start_matches('-');
+        match opt.value {
+            None => result.push_str(&format!("# {} = false\n", key)),
+            Some(value) => result.push_str(&format!("# {} = \"<{}>\"\n", key, value)),
+        }
+    }
+    result
+}
+
+/// An option's description, minus any footnote markers like `[1]` which refer to the help
+fn description(opt: &OptionInfo) -> String {
+    let mut result = opt.description.clone();
+    while let Some(start) = result.rfind('[') {
+        if result[start..].ends_with(']') {
+            result.truncate(start);
+        } else {
+            break;
+        }
+    }
+    result.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn process_wide_options_are_rejected_in_rules() {
+        let file = Path::new("/work/.watchfs.toml");
+        let config = parse("once = true\n[[rule]]\ndir = \"a\"\n", file).unwrap();
+        assert!(config.flags.contains(&String::from("--once")));
+        let err = parse("[[rule]]\ndir = \"a\"\nonce = true\n", file).unwrap_err();
+        assert!(err.starts_with("in [[rule]] 1: 'once'"), "{}", err);
+        let err = parse("[[rule]]\n[[rule]]\nstatus-to = \"x\"\n", file).unwrap_err();
+        assert!(err.starts_with("in [[rule]] 2: 'status-to'"), "{}", err);
+    }
+}
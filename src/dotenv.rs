@@ -0,0 +1,77 @@
+//! Environment files for `--env-file`, in the dotenv format - one `NAME=value` per line, with
+//! blank lines and lines starting with `#` skipped, and an optional leading `export `.
+//!
+//! A value may be double-quoted, in which case `\n`, `\t`, `\"` and `\\` are unescaped, or
+//! single-quoted, in which case it is taken literally; either way a quoted value must end on
+//! the line it starts on.  An unquoted value ends at a `#` preceded by whitespace, and has
+//! surrounding whitespace trimmed.  Variables are not expanded.
+
+/// Parse the text of an environment file into names and values, in the order they appear
+pub(crate) fn parse(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut result = Vec::new();
+    for (ix, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((name, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected NAME=value", ix + 1));
+        };
+        let name = name.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("line {}: invalid variable name '{}'", ix + 1, name));
+        }
+        let value = unquote(value.trim()).map_err(|e| format!("line {}: {}", ix + 1, e))?;
+        result.push((name.to_string(), value));
+    }
+    Ok(result)
+}
+
+/// A value, unquoted, minus any trailing comment
+fn unquote(text: &str) -> Result<String, String> {
+    let (quoted, rest) = match text.chars().next() {
+        Some('"') => double_quoted(&text[1..])?,
+        Some('\'') => match text[1..].split_once('\'') {
+            Some((quoted, rest)) => (quoted.to_string(), rest),
+            None => return Err(String::from("unterminated ' quote")),
+        },
+        _ => {
+            let end = text
+                .char_indices()
+                .find(|(ix, c)| *c == '#' && text[..*ix].ends_with(char::is_whitespace))
+                .map(|(ix, _)| ix)
+                .unwrap_or(text.len());
+            return Ok(text[..end].trim_end().to_string());
+        }
+    };
+    let rest = rest.trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(quoted)
+    } else {
+        Err(format!("unexpected text after the quoted value: '{}'", rest))
+    }
+}
+
+/// The value of a double-quoted string, with its escapes applied, and the text after it
+fn double_quoted(text: &str) -> Result<(String, &str), String> {
+    let mut result = String::new();
+    let mut chars = text.char_indices();
+    while let Some((ix, c)) = chars.next() {
+        match c {
+            '"' => return Ok((result, &text[ix + 1..])),
+            '\\' => match chars.next() {
+                Some((_, 'n')) => result.push('\n'),
+                Some((_, 't')) => result.push('\t'),
+                Some((_, c @ ('"' | '\\'))) => result.push(c),
+                Some((_, c)) => {
+                    result.push('\\');
+                    result.push(c);
+                }
+                None => break,
+            },
+            c => result.push(c),
+        }
+    }
+    Err(String::from("unterminated \" quote"))
+}
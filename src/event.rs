@@ -0,0 +1,88 @@
+//! The JSON record emitted for each batch of changes when running with `--json`.
+//!
+//! This is an integration point other programs parse, so its structure is part of
+//! the contract of this crate:  fields may be *added* without changing the version,
+//! but any change which removes or changes the meaning or type of an existing field
+//! MUST increment `SCHEMA_VERSION`.
+use chrono::{DateTime, Local, SecondsFormat};
+use notify::Op;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// The version of the structure of `EmitEvent`
+pub(crate) const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+pub(crate) struct EmitEvent<'a> {
+    /// Always `SCHEMA_VERSION`
+    pub version: u32,
+    /// When the batch was emitted, as RFC 3339 in local time
+    pub timestamp: String,
+    /// The directory being watched
+    pub dir: &'a str,
+    /// The changed paths, in the same form (and order) they are passed to the command
+    pub paths: &'a [String],
+    /// The kinds of change observed for each path during the batch, keyed by the
+    /// same strings as `paths`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ops: Option<BTreeMap<&'a str, Vec<&'static str>>>,
+}
+
+impl<'a> EmitEvent<'a> {
+    pub fn new(
+        when: DateTime<Local>,
+        dir: &'a str,
+        paths: &'a [String],
+        ops: Option<BTreeMap<&'a str, Vec<&'static str>>>,
+    ) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            timestamp: when.to_rfc3339_opts(SecondsFormat::Millis, false),
+            dir,
+            paths,
+            ops,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("EmitEvent is always serializable")
+    }
+
+    /// Indented, over several lines - but still a single JSON value, so a stream of them can
+    /// be read by any parser which accepts concatenated values
+    pub fn to_json_pretty(&self) -> String {
+        serde_json::to_string_pretty(self).expect("EmitEvent is always serializable")
+    }
+}
+
+const OP_NAMES: [(Op, &str); 7] = [
+    (Op::CREATE, "create"),
+    (Op::WRITE, "write"),
+    (Op::CLOSE_WRITE, "close_write"),
+    (Op::REMOVE, "remove"),
+    (Op::RENAME, "rename"),
+    (Op::CHMOD, "chmod"),
+    (Op::RESCAN, "rescan"),
+];
+
+/// The names of the operations set in a notify `Op`, as they appear in JSON output
+pub(crate) fn op_names(op: Op) -> Vec<&'static str> {
+    let mut result = Vec::new();
+    for (flag, name) in OP_NAMES {
+        if op.contains(flag) {
+            result.push(name);
+        }
+    }
+    result
+}
+
+/// The inverse of `op_names` - unknown names are ignored
+pub(crate) fn op_from_names<'a>(names: impl Iterator<Item = &'a str>) -> Op {
+    let mut result = Op::empty();
+    for name in names {
+        if let Some((flag, _)) = OP_NAMES.iter().find(|(_, n)| *n == name) {
+            result.insert(*flag);
+        }
+    }
+    result
+}
@@ -0,0 +1,104 @@
+//! Falling back from the platform's native file watcher to polling (`--poll-fallback`), for
+//! filesystems where native events stop arriving - some container and VM mounts deliver them
+//! at first, then silently stop.
+//!
+//! Each interval, a probe file is written to the watched directory, and removed at the next;
+//! if the watcher has not reported it by then, or reports an error, the caller switches to a
+//! polling watcher, which finds changes by comparing modification times.
+use crate::cleanup;
+use log::debug;
+use notify::{PollWatcher, RawEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often the polling watcher rescans the tree
+const POLL_INTERVAL_MS: u32 = 2000;
+
+/// The file watcher in use
+pub(crate) enum Backend {
+    Native(RecommendedWatcher),
+    Poll(PollWatcher),
+}
+
+impl Backend {
+    /// A polling watcher sending events to `tx`
+    pub(crate) fn poll(tx: Sender<RawEvent>) -> notify::Result<Self> {
+        PollWatcher::with_delay_ms(tx, POLL_INTERVAL_MS).map(Backend::Poll)
+    }
+
+    pub(crate) fn watch(&mut self, path: &Path, mode: RecursiveMode) -> notify::Result<()> {
+        match self {
+            Backend::Native(watcher) => watcher.watch(path, mode),
+            Backend::Poll(watcher) => watcher.watch(path, mode),
+        }
+    }
+
+    pub(crate) fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        match self {
+            Backend::Native(watcher) => watcher.unwatch(path),
+            Backend::Poll(watcher) => watcher.unwatch(path),
+        }
+    }
+
+    pub(crate) fn is_polling(&self) -> bool {
+        matches!(self, Backend::Poll(_))
+    }
+}
+
+/// Periodically checks that the native watcher still reports changes
+pub(crate) struct Probe {
+    path: PathBuf,
+    seen: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Probe {
+    /// Start writing a probe file in `dir` every `interval`, calling `on_stall` each time one
+    /// goes unreported
+    pub(crate) fn start<F>(dir: &Path, interval: Duration, on_stall: F) -> Self
+    where
+        F: Fn() + Send + 'static,
+    {
+        let path = dir.join(format!(".watchfs-probe-{}", std::process::id()));
+        cleanup::register(&path);
+        let result = Probe {
+            path: path.clone(),
+            seen: Arc::new(AtomicBool::new(false)),
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+        let (seen, stopped) = (result.seen.clone(), result.stopped.clone());
+        std::thread::spawn(move || loop {
+            seen.store(false, Ordering::SeqCst);
+            if let Err(e) = std::fs::write(&path, b"") {
+                debug!("Could not write probe file {:?}: {}", path, e);
+            }
+            std::thread::sleep(interval);
+            let _ = std::fs::remove_file(&path);
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            if !seen.load(Ordering::SeqCst) {
+                on_stall();
+            }
+        });
+        result
+    }
+
+    /// Whether a changed path is the probe file, which is never passed on
+    pub(crate) fn is_probe(&self, path: &Path) -> bool {
+        path == self.path
+    }
+
+    /// Note that the watcher reported the probe file
+    pub(crate) fn seen(&self) {
+        self.seen.store(true, Ordering::SeqCst);
+    }
+
+    /// Stop probing, once polling
+    pub(crate) fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
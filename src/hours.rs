@@ -0,0 +1,80 @@
+//! The `--active-hours` window, in local time, outside which batches of changes wait for it to
+//! open (or with `--drop-outside-active-hours`, are dropped) instead of running the command.
+//!
+//! A window is written `HH:MM-HH:MM`, starting at the first time and ending just before the
+//! second; one which ends earlier in the day than it starts wraps past midnight, so
+//! `22:00-06:00` is overnight.
+use chrono::{DateTime, Duration, Local, NaiveTime, TimeZone};
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ActiveHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl ActiveHours {
+    /// Parse a window written as `HH:MM-HH:MM`
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        let Some((start, end)) = text.split_once('-') else {
+            return Err(format!(
+                "expected a start and end time such as 09:00-18:00, not '{}'",
+                text
+            ));
+        };
+        let time = |part: &str| {
+            NaiveTime::parse_from_str(part.trim(), "%H:%M")
+                .map_err(|_| format!("'{}' is not a time of day as HH:MM", part.trim()))
+        };
+        let (start, end) = (time(start)?, time(end)?);
+        if start == end {
+            return Err(format!(
+                "the window {} starts and ends at the same time",
+                text
+            ));
+        }
+        Ok(ActiveHours { start, end })
+    }
+
+    /// Whether a time falls within the window
+    pub(crate) fn contains(&self, at: DateTime<Local>) -> bool {
+        let time = at.time();
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// `at` if it falls within the window, otherwise when the window next opens after it
+    pub(crate) fn next_open(&self, at: DateTime<Local>) -> DateTime<Local> {
+        if self.contains(at) {
+            return at;
+        }
+        let mut opens = at.date_naive().and_time(self.start);
+        if opens <= at.naive_local() {
+            opens += Duration::days(1);
+        }
+        // A start in the hour skipped when clocks go forward opens the window once it is over
+        Local
+            .from_local_datetime(&opens)
+            .earliest()
+            .or_else(|| {
+                Local
+                    .from_local_datetime(&(opens + Duration::hours(1)))
+                    .earliest()
+            })
+            .unwrap_or(at)
+    }
+}
+
+impl Display for ActiveHours {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
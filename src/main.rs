@@ -1,24 +1,63 @@
 mod args;
+mod cleanup;
+mod completions;
+mod config;
+mod dotenv;
+mod event;
+mod fallback;
+mod hours;
+mod mirror;
+mod patterns;
+mod pidfile;
+mod pty;
+mod record;
+mod runlog;
+mod serve;
+mod sink;
+mod status;
+mod streams;
+mod symlinks;
+mod template;
 mod watch;
 
 use log::debug;
 use watch::Watch;
 
 fn main() {
-    // Initialize logging early - sets up the logger from RUST_LOG
-    env_logger::init();
+    // Removes any temporary files we create on exit, including on SIGINT/SIGTERM
+    let _cleanup = cleanup::Cleanup::install();
 
-    // Parse the command-line arguments
-    let args = args::Args::new();
+    // Parse the command-line arguments - one set for each [[rule]] of a configuration file
+    let rules = args::Args::new();
 
-    // If verbose log them
-    if args.verbose {
-        println!("Args:\n{:?}", args);
+    // Set up the logger from RUST_LOG, or if it is unset, the number of -v flags
+    let mut logger = env_logger::Builder::from_default_env();
+    if std::env::var_os("RUST_LOG").is_none() {
+        if let Some(level) = rules.iter().filter_map(args::Args::log_level).max() {
+            logger.filter_level(level);
+        }
     }
-    // Also log to the regular logger
-    debug!("Args: {}", args);
+    logger.init();
 
+    for args in &rules {
+        // If verbose log them
+        if args.verbose {
+            status::status_println!("Args:\n{:?}", args);
+        }
+        // Also log to the regular logger
+        debug!("Args: {}", args);
+    }
+
+    // Stdin and signals are read once, for every rule
+    let mut watches: Vec<Watch> = rules.into_iter().map(Watch::new).collect();
+    Watch::share_input(&watches);
+
+    // Every rule but the last is watched on a thread of its own
+    let last = watches.pop().expect("There are always arguments to watch with");
+    for watch in watches {
+        std::thread::spawn(move || watch.start());
+    }
     // This will block the main thread, using it to process filesystem events until
     // this process is killed.
-    Watch::new(args).start();
+    last.start();
 }
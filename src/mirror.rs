@@ -0,0 +1,109 @@
+//! Copying changes to another directory, for `--mirror-to` - each changed file is copied to the
+//! same place relative to the mirror as it has in the watched directory, with any missing
+//! parent directories created, and the copy of each deleted file or directory is removed.
+//!
+//! A directory created or moved into the tree is copied with everything in it, since the
+//! watcher may not report the files it already held.  Symlinks are copied as symlinks, never
+//! followed - one pointing at a directory above it would otherwise be copied forever.  Changed
+//! paths outside the watched directory - from `--also-watch` - have nowhere in the mirror to
+//! go, and are skipped.
+use crate::args::Change;
+use crate::status::status_eprintln;
+use log::debug;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Bring the mirror up to date with a batch of changed paths, each as watchfs saw it
+pub(crate) fn apply(dir: &Path, mirror: &Path, sources: &[PathBuf], changes: &[Change]) {
+    for (source, change) in sources.iter().zip(changes.iter()) {
+        let Ok(rest) = source.strip_prefix(dir) else {
+            debug!("Not mirroring {:?} - outside {:?}", source, dir);
+            continue;
+        };
+        if rest.as_os_str().is_empty() {
+            continue;
+        }
+        let target = mirror.join(rest);
+        let done = match change {
+            Change::Delete => remove(&target),
+            Change::Create | Change::Modify => copy(source, &target, *change == Change::Create),
+        };
+        match done {
+            Ok(()) => debug!("Mirrored {:?} {:?} to {:?}", change, source, target),
+            Err(e) => status_eprintln!(
+                "Could not mirror {} to {}: {}",
+                source.display(),
+                target.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// Remove the copy of a deleted path, if there is one
+fn remove(target: &Path) -> io::Result<()> {
+    let result = match fs::symlink_metadata(target) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(target),
+        Ok(_) => fs::remove_file(target),
+        Err(_) => return Ok(()),
+    };
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Copy a created or modified path over its copy, replacing a copy of another kind - a file
+/// where there was a directory, say
+fn copy(source: &Path, target: &Path, created: bool) -> io::Result<()> {
+    let meta = match fs::symlink_metadata(source) {
+        Ok(meta) => meta,
+        // Gone again since the batch was collected; the removal will be in a later one
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let existing = fs::symlink_metadata(target).ok();
+    if meta.file_type().is_symlink() {
+        if existing.is_some() {
+            remove(target)?;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        return copy_link(source, target);
+    }
+    if meta.is_dir() {
+        if existing.is_some_and(|existing| !existing.is_dir()) {
+            fs::remove_file(target)?;
+        }
+        fs::create_dir_all(target)?;
+        // A modified directory only had its entries changed, which arrive as changes of their own
+        if created {
+            for entry in fs::read_dir(source)? {
+                let entry = entry?;
+                copy(&entry.path(), &target.join(entry.file_name()), true)?;
+            }
+        }
+        return Ok(());
+    }
+    if existing.is_some_and(|existing| !existing.is_file()) {
+        remove(target)?;
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(source, target).map(|_| ())
+}
+
+/// Make `target` a symlink to wherever the symlink `source` points
+#[cfg(unix)]
+fn copy_link(source: &Path, target: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(source)?, target)
+}
+
+#[cfg(not(unix))]
+fn copy_link(source: &Path, _target: &Path) -> io::Result<()> {
+    debug!("Not mirroring symlink {:?}", source);
+    Ok(())
+}
@@ -0,0 +1,71 @@
+//! Pattern files for `--include-from` and `--exclude-from` - one pattern per line, with blank
+//! lines and lines starting with `#` skipped.
+//!
+//! Patterns are globs unless `--pattern-syntax regex` is passed.  A glob containing a `/` is
+//! matched against the path relative to the watched directory, one without against any
+//! trailing portion of it - so `*.log` matches log files anywhere, and `/build` or `docs/*.md`
+//! only there.  `*` and `?` do not match a `/`, `**` matches anything, and `[...]` is a set of
+//! characters (`[!...]` one not in the set).  A glob which matches a directory matches
+//! everything beneath it.  Regexes are also matched against the relative path - unlike `-f`
+//! regexes, which see the full path.
+use regex::Regex;
+
+/// Compile the lines of a pattern file
+pub(crate) fn compile(text: &str, regex: bool) -> Result<Vec<Regex>, String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let source = if regex {
+                line.to_string()
+            } else {
+                glob_to_regex(line)
+            };
+            Regex::new(&source).map_err(|e| format!("Invalid pattern '{}' - {}", line, e))
+        })
+        .collect()
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let anchored = glob.trim_end_matches('/').contains('/');
+    let glob = glob.trim_start_matches('/').trim_end_matches('/');
+    let mut result = String::from(if anchored { "^" } else { "(^|/)" });
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    // **/ also matches no directories at all
+                    chars.next();
+                    result.push_str("(.*/)?");
+                } else {
+                    result.push_str(".*");
+                }
+            }
+            '*' => result.push_str("[^/]*"),
+            '?' => result.push_str("[^/]"),
+            '[' => {
+                result.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    result.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        result.push('\\');
+                    }
+                    result.push(c);
+                }
+                result.push(']');
+            }
+            c => result.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    // Matching a directory matches what is in it
+    result.push_str("(/.*)?$");
+    result
+}
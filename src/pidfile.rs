@@ -0,0 +1,43 @@
+//! The `--child-pidfile`, holding the process id of the running command for tools outside
+//! watchfs to signal or health-check it.
+//!
+//! The file is replaced atomically - written beside itself and renamed into place - so a
+//! reader never sees it part-written, and is only removed by the exit of the process it names,
+//! so a command stopped by `--restart` cannot remove the file naming its replacement.
+use crate::cleanup;
+use crate::status::status_eprintln;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The process id in each file watchfs wrote - one per `[[rule]]` of a configuration file
+/// which has one; held while a file is changed
+static CURRENT: Mutex<BTreeMap<PathBuf, u32>> = Mutex::new(BTreeMap::new());
+
+/// Record that the command was started as process `pid`
+pub(crate) fn write(path: &Path, pid: u32) {
+    let mut current = CURRENT.lock().unwrap_or_else(|e| e.into_inner());
+    let temp = path.with_extension(format!("tmp-{}", std::process::id()));
+    let written = std::fs::write(&temp, format!("{}\n", pid)).and_then(|_| std::fs::rename(&temp, path));
+    match written {
+        Ok(()) => {
+            if current.insert(path.to_path_buf(), pid).is_none() {
+                cleanup::register(path);
+            }
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp);
+            status_eprintln!("Could not write {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Record that process `pid` exited, removing the file if it still names it
+pub(crate) fn exited(path: &Path, pid: u32) {
+    let mut current = CURRENT.lock().unwrap_or_else(|e| e.into_inner());
+    if current.get(path) == Some(&pid) {
+        current.remove(path);
+        let _ = std::fs::remove_file(path);
+        cleanup::unregister(path);
+    }
+}
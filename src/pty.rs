@@ -0,0 +1,68 @@
+//! Runs the command attached to a pseudo-terminal rather than inheriting our stdio, for
+//! tools which behave differently (colorizing, progress bars) when they see a terminal
+use crate::cleanup;
+use log::{debug, trace};
+use portable_pty::{native_pty_system, CommandBuilder, ExitStatus, PtySize};
+use std::io::{self, Error, Write};
+use std::process::Command;
+
+/// Run the command in a pty, copying its output to our stdout, and wait for it to exit; if
+/// `track` is true, it is registered to be terminated if watchfs exits while it runs, and if
+/// `clean_env` is true, it inherits none of our environment
+pub(crate) fn run(cmd: &Command, track: bool, clean_env: bool) -> io::Result<ExitStatus> {
+    let pair = native_pty_system()
+        .openpty(PtySize::default())
+        .map_err(to_io)?;
+
+    let mut builder = CommandBuilder::new(cmd.get_program());
+    builder.args(cmd.get_args());
+    if clean_env {
+        builder.env_clear();
+    }
+    for (name, value) in cmd.get_envs() {
+        match value {
+            Some(value) => builder.env(name, value),
+            None => builder.env_remove(name),
+        }
+    }
+    // Otherwise the pty system would start the command in the home directory
+    builder.cwd(std::env::current_dir()?);
+
+    let mut child = pair.slave.spawn_command(builder).map_err(to_io)?;
+    // Our copy of the slave side must be closed, or reads from the master never see EOF
+    drop(pair.slave);
+
+    let mut reader = pair.master.try_clone_reader().map_err(to_io)?;
+    let copier = std::thread::spawn(move || {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        // On Linux, reading the master after the child exits fails with EIO rather than
+        // returning EOF, so any error just means we are done
+        match io::copy(&mut reader, &mut out) {
+            Ok(bytes) => trace!("Copied {} bytes of pty output", bytes),
+            Err(e) => trace!("Pty output ended: {}", e),
+        }
+        let _ = out.flush();
+    });
+
+    // The pty system makes the child a session (and so process group) leader
+    let pid = child.process_id().filter(|_| track);
+    if let Some(pid) = pid {
+        cleanup::register_child(pid);
+    }
+    trace!("Enter wait for pty child {:?}", child);
+    let status = child.wait();
+    if let Some(pid) = pid {
+        cleanup::unregister_child(pid);
+    }
+    let status = status?;
+    drop(pair.master);
+    if copier.join().is_err() {
+        debug!("Pty output thread panicked");
+    }
+    Ok(status)
+}
+
+fn to_io<E: std::fmt::Display>(e: E) -> Error {
+    Error::other(e.to_string())
+}
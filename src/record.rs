@@ -0,0 +1,120 @@
+//! Recording raw file watcher events to a file (`--record`), and feeding them back through
+//! the pipeline without a real watcher (`--replay-events`), for reproducing reports of
+//! changes which did or did not trigger the command.  With `--replay-speed`, replayed events
+//! are spaced out as they were recorded, so the debouncing sees them arrive as it did then.
+//!
+//! The file is JSON lines, one event per line, e.g.
+//! `{"timestamp":"2023-05-01T12:00:00.000-04:00","path":"/some/file","ops":["write"]}`.
+//! Errors from the watcher are recorded with an `error` field and no `ops`.
+use crate::event::{op_from_names, op_names};
+use chrono::{DateTime, FixedOffset, Local, SecondsFormat};
+use log::{debug, error};
+use notify::RawEvent;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct RecordedEvent {
+    pub timestamp: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ops: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RecordedEvent {
+    fn new(event: &RawEvent) -> Self {
+        let (ops, error) = match &event.op {
+            Ok(op) => (op_names(*op).iter().map(|s| s.to_string()).collect(), None),
+            Err(e) => (Vec::new(), Some(e.to_string())),
+        };
+        Self {
+            timestamp: Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+            path: event
+                .path
+                .as_ref()
+                .map(|p| p.to_string_lossy().to_string()),
+            ops,
+            error,
+        }
+    }
+
+    fn at(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(&self.timestamp).ok()
+    }
+
+    fn to_raw(&self) -> RawEvent {
+        RawEvent {
+            path: self.path.as_ref().map(PathBuf::from),
+            op: match &self.error {
+                Some(e) => Err(notify::Error::Generic(e.clone())),
+                None => Ok(op_from_names(self.ops.iter().map(|s| s.as_str()))),
+            },
+            cookie: None,
+        }
+    }
+}
+
+/// Appends every raw event to a file, opened once at startup
+pub(crate) struct Recorder {
+    file: File,
+}
+
+impl Recorder {
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    pub fn record(&mut self, event: &RawEvent) {
+        let line = serde_json::to_string(&RecordedEvent::new(event))
+            .expect("RecordedEvent is always serializable");
+        if let Err(e) = writeln!(self.file, "{}", line).and_then(|_| self.file.flush()) {
+            error!("Could not record event: {}", e);
+        }
+    }
+}
+
+/// Read recorded events from a file on a background thread, passing each to `send`,
+/// then call `done`.  If `speed` is more than zero, wait between events for the time between
+/// their timestamps, divided by `speed`; otherwise send them all at once
+pub(crate) fn replay<S, D>(path: &str, speed: f64, send: S, done: D) -> std::io::Result<()>
+where
+    S: Fn(RawEvent) -> bool + Send + 'static,
+    D: FnOnce() + Send + 'static,
+{
+    let reader = BufReader::new(File::open(path)?);
+    std::thread::spawn(move || {
+        let mut last: Option<DateTime<FixedOffset>> = None;
+        for (ix, line) in reader.lines().enumerate() {
+            match line {
+                Ok(line) if line.trim().is_empty() => {}
+                Ok(line) => match serde_json::from_str::<RecordedEvent>(&line) {
+                    Ok(recorded) => {
+                        let at = recorded.at().filter(|_| speed > 0.0);
+                        if let (Some(last), Some(at)) = (last, at) {
+                            let gap = (at - last).to_std().unwrap_or_default();
+                            std::thread::sleep(gap.div_f64(speed));
+                        }
+                        last = at.or(last);
+                        if !send(recorded.to_raw()) {
+                            return;
+                        }
+                    }
+                    Err(e) => error!("Bad recorded event on line {}: {}", ix + 1, e),
+                },
+                Err(e) => {
+                    error!("Error reading recorded events: {}", e);
+                    break;
+                }
+            }
+        }
+        debug!("Replay complete");
+        done();
+    });
+    Ok(())
+}
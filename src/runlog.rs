@@ -0,0 +1,74 @@
+//! The outcome of each run of the command, appended to the `--run-log` file as one JSON object
+//! per line - for auditing and analysing builds over time, where `--json` records the batches
+//! of changes rather than what running the command on them did.
+//!
+//! Like the `--json` record, fields may be added, but existing ones keep their meaning.
+use crate::status::status_eprintln;
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+static LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Open the run log for appending; only the first call has any effect
+pub(crate) fn open(path: &str) -> std::io::Result<()> {
+    if LOG.get().is_none() {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let _ = LOG.set(Mutex::new(file));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct Record {
+    /// When the command was started, as RFC 3339 in local time
+    timestamp: String,
+    /// How many changed paths the command was run for
+    paths: usize,
+    /// The command's exit code - null if it could not be started or was ended by a signal
+    exit_code: Option<i32>,
+    /// Whether the run counted as a success
+    success: bool,
+    /// How long the command ran for
+    duration_ms: u128,
+}
+
+/// A run of the command, from when it was started
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Run {
+    at: DateTime<Local>,
+    started: Instant,
+    paths: usize,
+}
+
+impl Run {
+    pub(crate) fn start(paths: usize) -> Self {
+        Run {
+            at: Local::now(),
+            started: Instant::now(),
+            paths,
+        }
+    }
+
+    /// Append the record of this run, if there is a run log
+    pub(crate) fn finish(&self, exit_code: Option<i32>, success: bool) {
+        let Some(file) = LOG.get() else {
+            return;
+        };
+        let record = Record {
+            timestamp: self.at.to_rfc3339_opts(SecondsFormat::Millis, false),
+            paths: self.paths,
+            exit_code,
+            success,
+            duration_ms: self.started.elapsed().as_millis(),
+        };
+        let line = serde_json::to_string(&record).expect("Record is always serializable");
+        let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+            status_eprintln!("Could not write to the run log: {}", e);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Serves batches of changes to clients connected to a socket (`--listen`), turning watchfs
+//! into a change-notification daemon for editors and other tools.
+//!
+//! The wire format is the same as `--json`: each batch is sent to every connected client as
+//! a single line of JSON terminated by `\n`.  Clients never need to send anything; a client
+//! which disconnects (or whose writes fail) is simply dropped.
+#[cfg(unix)]
+use crate::cleanup;
+use log::{debug, info};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+
+type Clients = Arc<Mutex<Vec<Box<dyn Write + Send>>>>;
+
+pub(crate) struct Server {
+    clients: Clients,
+}
+
+impl Server {
+    /// Listen on `addr` - a TCP `host:port`, or on unix, the path of a unix domain socket
+    pub fn listen(addr: &str) -> io::Result<Self> {
+        let clients: Clients = Arc::new(Mutex::new(Vec::new()));
+        if let Ok(sock) = addr.parse::<SocketAddr>() {
+            let listener = TcpListener::bind(sock)?;
+            info!("Listening for clients on {}", sock);
+            let accepted = clients.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    debug!("Client connected from {:?}", stream.peer_addr());
+                    let _ = stream.set_nodelay(true);
+                    accepted.lock().unwrap().push(Box::new(stream));
+                }
+            });
+        } else {
+            listen_unix(addr, clients.clone())?;
+        }
+        Ok(Self { clients })
+    }
+
+    /// Send one line to every connected client, dropping any that have gone away
+    pub fn broadcast(&self, line: &str) {
+        let mut clients = self.clients.lock().unwrap();
+        let before = clients.len();
+        clients.retain_mut(|client| {
+            client
+                .write_all(line.as_bytes())
+                .and_then(|_| client.write_all(b"\n"))
+                .and_then(|_| client.flush())
+                .is_ok()
+        });
+        if clients.len() < before {
+            debug!("{} clients disconnected", before - clients.len());
+        }
+        debug!("Sent batch to {} clients", clients.len());
+    }
+}
+
+#[cfg(unix)]
+fn listen_unix(path: &str, clients: Clients) -> io::Result<()> {
+    use std::os::unix::net::UnixListener;
+    // A socket file left over from a previous run would make bind fail
+    if std::fs::metadata(path)
+        .map(|m| {
+            use std::os::unix::fs::FileTypeExt;
+            m.file_type().is_socket()
+        })
+        .unwrap_or(false)
+    {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    cleanup::register(path);
+    info!("Listening for clients on {}", path);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            debug!("Client connected to unix socket");
+            clients.lock().unwrap().push(Box::new(stream));
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen_unix(path: &str, _clients: Clients) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("'{}' is not a host:port address", path),
+    ))
+}
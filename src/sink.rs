@@ -0,0 +1,224 @@
+//! Where each batch of changes goes.  Every configured sink receives the same batch, so that
+//! one run of watchfs can, say, run a build and keep an audit log of what triggered it.
+//!
+//! The JSON sinks - `--json` to stdout, `--json-file`, `--fifo` and `--listen` - are sent the
+//! batch first, in that order, and then the command is run on it; all of them see the same
+//! record, described in the `event` module.  With `--mirror-to`, the mirror is brought up to
+//! date before any of them.
+use crate::args::{Args, Change};
+use crate::cleanup;
+use crate::event::{op_names, EmitEvent};
+use crate::mirror;
+use crate::serve::Server;
+use crate::status::status_eprintln;
+use chrono::{DateTime, Local};
+use log::debug;
+use notify::Op;
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A batch of changed paths, as passed to the command
+pub(crate) struct Batch<'a> {
+    /// The watched directory
+    dir: &'a str,
+    /// When the batch was emitted
+    at: DateTime<Local>,
+    pub paths: Vec<String>,
+    pub ops: Vec<Op>,
+    pub changes: Vec<Change>,
+    /// The changed paths as watchfs saw them, before --relativize or --slash
+    sources: Vec<PathBuf>,
+    /// The JSON record of the batch, made once for whichever sinks need it
+    json: OnceCell<String>,
+}
+
+impl<'a> Batch<'a> {
+    pub(crate) fn new(
+        dir: &'a str,
+        paths: Vec<String>,
+        ops: Vec<Op>,
+        changes: Vec<Change>,
+        sources: Vec<PathBuf>,
+    ) -> Self {
+        Batch {
+            dir,
+            at: Local::now(),
+            paths,
+            ops,
+            changes,
+            sources,
+            json: OnceCell::new(),
+        }
+    }
+
+    fn event(&self) -> EmitEvent<'_> {
+        let op_map: BTreeMap<&str, Vec<&'static str>> = self
+            .paths
+            .iter()
+            .zip(self.ops.iter())
+            .map(|(p, op)| (p.as_str(), op_names(*op)))
+            .collect();
+        EmitEvent::new(self.at, self.dir, &self.paths, Some(op_map))
+    }
+
+    fn json(&self) -> &str {
+        self.json.get_or_init(|| self.event().to_json())
+    }
+}
+
+pub(crate) enum Sink {
+    /// Copy each changed file to this directory, for --mirror-to
+    Mirror(PathBuf),
+    /// Print each batch as JSON to stdout, for --json and --json-pretty
+    Stdout { pretty: bool },
+    /// Append each batch as a line of JSON to a file, for --json-file
+    File { path: String, file: Mutex<File> },
+    /// Write each batch as a line of JSON to a named pipe, for --fifo, whenever something is
+    /// reading from it
+    Fifo(PathBuf),
+    /// Send each batch to connected clients, for --listen
+    Server(Server),
+    /// Run the command - which the caller does, since what that involves (confirmation,
+    /// pausing on failure and so on) is watcher state
+    Command,
+}
+
+impl Sink {
+    /// The sinks `args` calls for, in the order batches are passed to them - exiting if one
+    /// cannot be opened
+    pub(crate) fn all(args: &Args) -> Vec<Sink> {
+        let mut result = Vec::new();
+        if let Some(dir) = &args.mirror_to {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                status_eprintln!(
+                    "Could not create {} to mirror changes to: {}",
+                    dir.display(),
+                    e
+                );
+                cleanup::exit(15);
+            }
+            result.push(Sink::Mirror(dir.clone()));
+        }
+        if args.json {
+            result.push(Sink::Stdout {
+                pretty: args.json_pretty,
+            });
+        }
+        if let Some(path) = &args.json_file {
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => result.push(Sink::File {
+                    path: path.clone(),
+                    file: Mutex::new(file),
+                }),
+                Err(e) => {
+                    status_eprintln!("Could not open {} to write batches to: {}", path, e);
+                    cleanup::exit(15);
+                }
+            }
+        }
+        if let Some(path) = &args.fifo {
+            if let Err(e) = make_fifo(path) {
+                status_eprintln!("Could not use {} as a named pipe: {}", path.display(), e);
+                cleanup::exit(15);
+            }
+            result.push(Sink::Fifo(path.clone()));
+        }
+        if let Some(addr) = &args.listen {
+            match Server::listen(addr) {
+                Ok(server) => result.push(Sink::Server(server)),
+                Err(e) => {
+                    status_eprintln!("Could not listen on {}: {}", addr, e);
+                    cleanup::exit(17);
+                }
+            }
+        }
+        result.push(Sink::Command);
+        result
+    }
+
+    /// Pass a batch to this sink, unless it is the command
+    pub(crate) fn send(&self, batch: &Batch) {
+        match self {
+            Sink::Mirror(dir) => {
+                mirror::apply(Path::new(batch.dir), dir, &batch.sources, &batch.changes)
+            }
+            Sink::Stdout { pretty: true } => println!("{}", batch.event().to_json_pretty()),
+            Sink::Stdout { pretty: false } => println!("{}", batch.json()),
+            Sink::File { path, file } => {
+                let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                if let Err(e) = writeln!(file, "{}", batch.json()).and_then(|_| file.flush()) {
+                    status_eprintln!("Could not write the batch to {}: {}", path, e);
+                }
+            }
+            Sink::Fifo(path) => match open_fifo(path) {
+                Ok(mut fifo) => {
+                    if let Err(e) = writeln!(fifo, "{}", batch.json()) {
+                        debug!("Could not write the batch to {:?}: {}", path, e);
+                    }
+                }
+                // Nothing is reading, so there is no one to miss it
+                Err(e) => debug!("Not writing the batch to {:?}: {}", path, e),
+            },
+            Sink::Server(server) => server.broadcast(batch.json()),
+            Sink::Command => {}
+        }
+    }
+}
+
+/// Create the named pipe if it does not exist, removing it again on exit
+#[cfg(unix)]
+fn make_fifo(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.file_type().is_fifo() => Ok(()),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "it exists and is not a named pipe",
+        )),
+        Err(_) => {
+            let name = std::ffi::CString::new(path.as_os_str().as_bytes())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            if unsafe { libc::mkfifo(name.as_ptr(), 0o600) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            cleanup::register(path);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn make_fifo(_path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "named pipes are only supported on unix",
+    ))
+}
+
+/// Open the named pipe for writing without waiting for a reader, failing if there is none
+#[cfg(unix)]
+fn open_fifo(path: &Path) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    let file = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    // Once there is a reader, a batch larger than the pipe's buffer waits for it to catch up
+    let fd = file.as_raw_fd();
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK);
+    }
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn open_fifo(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().write(true).open(path)
+}
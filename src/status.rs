@@ -0,0 +1,69 @@
+//! Where watchfs's own messages go (`--status-to`), so stdout can carry nothing but the
+//! `--json` stream or the command's output when piping.
+//!
+//! Messages are written with `status_println!` and `status_eprintln!`, which behave like
+//! `println!` and `eprintln!` until a destination is set.  Help, usage errors and the
+//! `--json` records themselves are not status messages.
+use std::fmt::Arguments;
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+#[derive(Debug)]
+pub(crate) enum Sink {
+    Stdout,
+    Stderr,
+    File(Mutex<File>),
+    Null,
+}
+
+/// Send all status messages to `sink` from now on; only the first call has any effect
+pub(crate) fn set(sink: Sink) {
+    let _ = SINK.set(sink);
+}
+
+/// Write a line to the status destination, or to stderr or stdout if none was set
+pub(crate) fn write_line(to_stderr: bool, message: Arguments) {
+    match SINK.get() {
+        Some(Sink::Stdout) => println!("{}", message),
+        Some(Sink::Stderr) => eprintln!("{}", message),
+        Some(Sink::File(file)) => {
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            // Nowhere left to report a failure to write to the status file
+            let _ = writeln!(file, "{}", message).and_then(|_| file.flush());
+        }
+        Some(Sink::Null) => {}
+        None if to_stderr => eprintln!("{}", message),
+        None => println!("{}", message),
+    }
+}
+
+/// Whether status messages - those to stderr if `to_stderr` - are going to a terminal
+pub(crate) fn is_terminal(to_stderr: bool) -> bool {
+    use std::io::IsTerminal;
+    match SINK.get() {
+        Some(Sink::Stdout) => std::io::stdout().is_terminal(),
+        Some(Sink::Stderr) => std::io::stderr().is_terminal(),
+        Some(Sink::File(_) | Sink::Null) => false,
+        None if to_stderr => std::io::stderr().is_terminal(),
+        None => std::io::stdout().is_terminal(),
+    }
+}
+
+/// Like `println!`, for a status message
+macro_rules! status_println {
+    ($($arg:tt)*) => {
+        $crate::status::write_line(false, format_args!($($arg)*))
+    };
+}
+
+/// Like `eprintln!`, for a status message
+macro_rules! status_eprintln {
+    ($($arg:tt)*) => {
+        $crate::status::write_line(true, format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {status_eprintln, status_println};
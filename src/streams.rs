@@ -0,0 +1,163 @@
+//! Relaying a command's output through watchfs rather than letting it inherit our stdout and
+//! stderr - to label each line with the stream it came from (`--tag-streams`), so stderr
+//! stands out from stdout in noisy output, to stamp each line with the time it was read
+//! (`--timestamp-output`), for correlating output with changes, and to cap how much of it is
+//! passed on per run (`--max-output-bytes`).
+//!
+//! Labels are colored when the stream they are written to is a terminal, unless the
+//! `NO_COLOR` environment variable is set.
+use chrono::Local;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::process::Child;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const OUT_LABEL: &str = "out|";
+const ERR_LABEL: &str = "err|";
+const OUT_COLOR: &str = "\x1b[2m";
+const ERR_COLOR: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+const TRUNCATED: &str = "...(truncated)\n";
+
+/// How long to wait after the child exits for its output to be relayed - a background process
+/// it started may hold the pipes open indefinitely
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The bytes of output a run may still pass on, shared by its stdout and stderr
+#[derive(Debug)]
+struct Budget {
+    remaining: Option<usize>,
+    exhausted: bool,
+}
+
+impl Budget {
+    /// How many of `len` bytes may be written, and whether the truncation marker should be
+    /// written after them
+    fn take(&mut self, len: usize) -> (usize, bool) {
+        match self.remaining.as_mut() {
+            None => (len, false),
+            Some(remaining) if *remaining >= len => {
+                *remaining -= len;
+                (len, false)
+            }
+            Some(remaining) => {
+                let allowed = std::mem::take(remaining);
+                let mark = !self.exhausted;
+                self.exhausted = true;
+                (allowed, mark)
+            }
+        }
+    }
+}
+
+/// What is written before each line relayed - the time it was read, its stream's label, both
+/// or neither
+#[derive(Debug, Clone)]
+struct Prefix {
+    /// The strftime format of the time, if lines are timestamped
+    timestamps: Option<String>,
+    label: Option<String>,
+}
+
+impl Prefix {
+    fn now(&self) -> Option<String> {
+        match (&self.timestamps, &self.label) {
+            (None, None) => None,
+            (None, Some(label)) => Some(label.clone()),
+            (Some(format), label) => Some(format!(
+                "{} {}",
+                Local::now().format(format),
+                label.as_deref().unwrap_or("")
+            )),
+        }
+    }
+}
+
+/// Start relaying the piped stdout and stderr of a child, labelling lines if `tag` is true,
+/// timestamping them in the strftime `timestamps` format if set, and passing on at most
+/// `max_bytes` of its output if set; pass the result to `drain` after it exits, so its output
+/// is written before anything that follows
+pub(crate) fn relay(
+    child: &mut Child,
+    tag: bool,
+    timestamps: Option<&str>,
+    max_bytes: Option<usize>,
+) -> Vec<JoinHandle<()>> {
+    let budget = Arc::new(Mutex::new(Budget {
+        remaining: max_bytes,
+        exhausted: false,
+    }));
+    let mut result = Vec::with_capacity(2);
+    let prefix = |label| Prefix {
+        timestamps: timestamps.map(String::from),
+        label,
+    };
+    if let Some(out) = child.stdout.take() {
+        let prefix = prefix(label(tag, OUT_LABEL, OUT_COLOR, io::stdout().is_terminal()));
+        let budget = budget.clone();
+        result.push(thread::spawn(move || {
+            copy_lines(out, io::stdout(), prefix, &budget)
+        }));
+    }
+    if let Some(err) = child.stderr.take() {
+        let prefix = prefix(label(tag, ERR_LABEL, ERR_COLOR, io::stderr().is_terminal()));
+        result.push(thread::spawn(move || {
+            copy_lines(err, io::stderr(), prefix, &budget)
+        }));
+    }
+    result
+}
+
+/// Wait briefly for relaying threads to finish
+pub(crate) fn drain(relays: Vec<JoinHandle<()>>) {
+    let until = Instant::now() + DRAIN_TIMEOUT;
+    while relays.iter().any(|r| !r.is_finished()) && Instant::now() < until {
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+fn label(tag: bool, text: &str, color: &str, terminal: bool) -> Option<String> {
+    if !tag {
+        None
+    } else if terminal && std::env::var_os("NO_COLOR").is_none() {
+        Some(format!("{}{}{} ", color, text, RESET))
+    } else {
+        Some(format!("{} ", text))
+    }
+}
+
+fn copy_lines<R: Read, W: Write>(from: R, mut to: W, prefix: Prefix, budget: &Mutex<Budget>) {
+    let mut reader = BufReader::new(from);
+    let mut line = Vec::new();
+    // Read bytes rather than strings, so output which is not UTF-8 is passed through as-is
+    while let Ok(count) = reader.read_until(b'\n', &mut line) {
+        if count == 0 {
+            break;
+        }
+        // Once the budget is spent, keep reading so the child never blocks on a full pipe
+        let (allowed, mark) = budget.lock().unwrap().take(line.len());
+        if allowed > 0 || mark {
+            line.truncate(allowed);
+            if let Some(prefix) = prefix.now() {
+                if allowed > 0 && !line.ends_with(b"\n") {
+                    line.push(b'\n');
+                }
+                if mark && allowed > 0 {
+                    // The marker gets a line, and so a prefix, of its own
+                    line.extend_from_slice(prefix.as_bytes());
+                }
+                line.splice(0..0, prefix.bytes());
+            } else if mark && !line.is_empty() && !line.ends_with(b"\n") {
+                line.push(b'\n');
+            }
+            if mark {
+                line.extend_from_slice(TRUNCATED.as_bytes());
+            }
+            if to.write_all(&line).and_then(|_| to.flush()).is_err() {
+                break;
+            }
+        }
+        line.clear();
+    }
+}
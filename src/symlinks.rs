@@ -0,0 +1,110 @@
+//! Watching the targets of symlinks in the watched tree which point outside of it
+//! (`--resolve-symlink-targets`), so edits to the real files count as changes to the links.
+//!
+//! The watcher does not follow symlinks, so a change to a linked-to file elsewhere is never
+//! seen.  At startup, the tree is searched for symlinks whose targets lie outside it; the
+//! directory of each target file (or the target directory itself) is watched as well, and
+//! events there are translated back to paths beneath the link.
+use crate::args::Args;
+use log::debug;
+use notify::RecursiveMode;
+use std::path::{Path, PathBuf};
+
+/// Symlinks found in the watched tree, and what needs watching to notice changes to them
+#[derive(Debug, Default)]
+pub(crate) struct Links {
+    /// Resolved targets outside the tree, and the in-tree links to report them as
+    targets: Vec<(PathBuf, PathBuf)>,
+    /// Extra directories to watch, each once
+    dirs: Vec<(PathBuf, RecursiveMode)>,
+    /// The directories watched anyway
+    roots: Vec<PathBuf>,
+}
+
+impl Links {
+    /// Find symlinks pointing outside the watched directories
+    pub(crate) fn discover(args: &Args) -> Self {
+        let mut result = Links::default();
+        let roots = args.watch_roots();
+        result.roots = roots.iter().map(|(dir, _)| dir.clone()).collect();
+        for (dir, mode) in &roots {
+            result.scan(dir, *mode == RecursiveMode::Recursive);
+        }
+        debug!("Symlink targets outside the tree: {:?}", result.targets);
+        result
+    }
+
+    fn scan(&mut self, dir: &Path, recursive: bool) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            let path = entry.path();
+            if file_type.is_symlink() {
+                // Dangling links and loops cannot be resolved, and are skipped
+                if let Ok(target) = std::fs::canonicalize(&path) {
+                    self.add(path, target);
+                }
+            } else if recursive && file_type.is_dir() {
+                self.scan(&path, recursive);
+            }
+        }
+    }
+
+    fn add(&mut self, link: PathBuf, target: PathBuf) {
+        if self.roots.iter().any(|root| target.starts_with(root)) {
+            // Inside the tree, so seen anyway
+            return;
+        }
+        let watch = if target.is_dir() {
+            (target.clone(), RecursiveMode::Recursive)
+        } else if let Some(parent) = target.parent() {
+            (parent.to_path_buf(), RecursiveMode::NonRecursive)
+        } else {
+            return;
+        };
+        let covered = self.dirs.iter_mut().any(|(dir, mode)| {
+            if *dir == watch.0 {
+                if watch.1 == RecursiveMode::Recursive {
+                    *mode = RecursiveMode::Recursive;
+                }
+                true
+            } else {
+                *mode == RecursiveMode::Recursive && watch.0.starts_with(dir)
+            }
+        });
+        if !covered {
+            self.dirs.push(watch);
+        }
+        // Several links may share a target, and a change is reported for each
+        self.targets.push((target, link));
+    }
+
+    /// The additional directories to watch
+    pub(crate) fn watch_dirs(&self) -> &[(PathBuf, RecursiveMode)] {
+        &self.dirs
+    }
+
+    /// Map a path from the watcher back into the tree - to beneath each link to it if it is a
+    /// path to or beneath a link's target, or to nothing if it is merely something else in a
+    /// directory watched for the sake of a link
+    pub(crate) fn translate(&self, path: PathBuf) -> Vec<PathBuf> {
+        if self.targets.is_empty() || self.roots.iter().any(|root| path.starts_with(root)) {
+            return vec![path];
+        }
+        let mut result = Vec::new();
+        for (target, link) in &self.targets {
+            if let Ok(rest) = path.strip_prefix(target) {
+                result.push(if rest.as_os_str().is_empty() {
+                    link.clone()
+                } else {
+                    link.join(rest)
+                });
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,64 @@
+//! Renders `--command-template` files into a shell command for each batch of changes.
+//!
+//! Templates use [TinyTemplate](https://docs.rs/tinytemplate) syntax, and have access to:
+//!
+//! * `paths` - the changed paths (relativized if `-r` is set)
+//! * `count` - the number of changed paths
+//! * `dir` - the directory being watched
+//!
+//! Values are inserted verbatim; use the `quote` formatter (`{dir | quote}`) to shell-quote
+//! a value - applied to `paths` it quotes each path and joins them with spaces.
+use crate::args::maybe_quote_or_escape;
+use serde::Serialize;
+use serde_json::Value;
+use tinytemplate::{format_unescaped, TinyTemplate};
+
+const NAME: &str = "command";
+
+#[derive(Serialize)]
+struct Context<'a> {
+    paths: &'a [String],
+    count: usize,
+    dir: &'a str,
+}
+
+/// Render the template text for a batch of changes
+pub(crate) fn render(text: &str, paths: &[String], dir: &str) -> Result<String, String> {
+    let mut tt = TinyTemplate::new();
+    tt.set_default_formatter(&format_unescaped);
+    tt.add_formatter("quote", quote);
+    tt.add_template(NAME, text).map_err(|e| e.to_string())?;
+    let context = Context {
+        paths,
+        count: paths.len(),
+        dir,
+    };
+    tt.render(NAME, &context)
+        .map(|rendered| rendered.trim().to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Check that the template text parses, without rendering it
+pub(crate) fn validate(text: &str) -> Result<(), String> {
+    let mut tt = TinyTemplate::new();
+    tt.add_template(NAME, text).map_err(|e| e.to_string())
+}
+
+fn quote(value: &Value, output: &mut String) -> tinytemplate::error::Result<()> {
+    match value {
+        Value::Array(items) => {
+            for (ix, item) in items.iter().enumerate() {
+                if ix > 0 {
+                    output.push(' ');
+                }
+                quote(item, output)?;
+            }
+            Ok(())
+        }
+        Value::String(st) => {
+            output.push_str(maybe_quote_or_escape(st).as_str());
+            Ok(())
+        }
+        other => format_unescaped(other, output),
+    }
+}
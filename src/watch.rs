@@ -1,155 +1,1668 @@
-use crate::args::Args;
+use crate::args::{device_of, Args, Change};
+use crate::cleanup;
+use crate::fallback::{Backend, Probe};
+use crate::record::{self, Recorder};
+use crate::sink::{Batch, Sink};
+use crate::status::{self, status_eprintln, status_println};
+use crate::symlinks::Links;
 use chrono::{DateTime, Local};
+use indexmap::IndexMap;
 use log::{debug, error, info, trace};
-use notify::{raw_watcher, Op, Watcher};
-use std::collections::BTreeSet;
+use notify::{raw_watcher, Op, RawEvent, RecursiveMode};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, VecDeque};
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvError, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
 use timer::*;
 
 pub(crate) struct Watch {
     args: Args,
     state: WatchState,
+    /// Everything which wakes up the main loop, including stdin and signals - which are
+    /// shared by every rule, so passed on by `share_input`
+    tx: Sender<Message>,
+    rx: Receiver<Message>,
 }
 
+/// How many rules have yet to finish before a SIGUSR2 drain or a finished replay exits
+static RULES_LEFT: AtomicUsize = AtomicUsize::new(1);
+
 impl Watch {
     pub fn new(args: Args) -> Self {
         let state = WatchState {
             timer: timer::Timer::new(),
             guard: None,
-            paths: Arc::new(Mutex::new(BTreeSet::new())),
+            exit_guard: None,
+            rng: match args.jitter_seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+            batch_delay: args.delay(),
+            batches_started: 0,
+            arrivals: VecDeque::new(),
+            batch_began: Local::now(),
+            deadline: None,
+            shared: Arc::new(Shared {
+                paths: Mutex::new(PathSet::new(args.preserve_order)),
+                last_run: Mutex::new(None),
+                last_failure: Mutex::new(None),
+                last_activity: Mutex::new(Local::now()),
+                paused: AtomicBool::new(false),
+                running: AtomicBool::new(false),
+                overlapped: AtomicBool::new(false),
+                sinks: Sink::all(&args),
+                answer: Mutex::new(None),
+                batch_timing: Mutex::new(None),
+                last_batch: Mutex::new(None),
+                finished: AtomicBool::new(false),
+            }),
         };
-        Self { args, state }
+        let (tx, rx) = channel();
+        Self {
+            args,
+            state,
+            tx,
+            rx,
+        }
+    }
+
+    /// Read stdin and handle signals once for the whole process, passing what arrives on to
+    /// the watcher for every rule
+    pub fn share_input(watches: &[Watch]) {
+        let txs: Vec<Sender<Message>> = watches.iter().map(|w| w.tx.clone()).collect();
+        if watches
+            .iter()
+            .any(|w| w.args.stdin_trigger || w.args.pause_on_error || w.args.confirm)
+        {
+            let shared = watches.iter().map(|w| w.state.shared.clone()).collect();
+            read_triggers(txs.clone(), shared);
+        }
+        handle_signals(txs);
+        RULES_LEFT.store(watches.len(), Ordering::SeqCst);
     }
 
     pub fn start(mut self) {
         info!("Enter watch on {}", self.args.path);
-        let (tx, rx) = channel();
+        let (tx, rx) = (self.tx, self.rx);
+        let (fs_tx, fs_rx) = channel();
 
-        // let mut watcher = watcher(tx, self.args.debounce_delay().to_std().unwrap()).unwrap();
-        let mut watcher = raw_watcher(tx).unwrap();
-        watcher
-            .watch(self.args.dir(), self.args.recursion_mode())
-            .expect(
-            "Could not create a watcher - no notify support in os? Folder deleted since startup?",
-        );
+        // Wait for anything the command depends on to be ready
+        self.args.warm_up();
+
+        let mut links = discover_links(&self.args);
 
-        // Harmless - we really do need it until program exit.
-        let a: &'static Args = Box::leak(Box::new(self.args));
+        // Native and polling watchers all send to clones of `fs_tx`, except that --poll-path's
+        // send to `polled_tx`, so the main loop can tell which watcher an event came from
+        let (polled_tx, polled_rx) = channel();
+        // With --replay-events, recorded events stand in for a real watcher
+        let mut watchers = if let Some(file) = &self.args.replay_events {
+            let done_tx = tx.clone();
+            let replay_tx = fs_tx.clone();
+            let sent = record::replay(
+                file,
+                self.args.replay_speed,
+                move |event| replay_tx.send(event).is_ok(),
+                move || {
+                    let _ = done_tx.send(Message::ReplayDone);
+                },
+            );
+            if let Err(e) = sent {
+                status_eprintln!("Could not read events to replay from {}: {}", file, e);
+                cleanup::exit(15);
+            }
+            None
+        } else {
+            Some(Watchers::start(&self.args, &links, &fs_tx, &polled_tx, &tx))
+        };
+        let mut recorder = self.args.record.as_ref().map(|file| {
+            Recorder::open(file).unwrap_or_else(|e| {
+                status_eprintln!("Could not open {} to record events: {}", file, e);
+                cleanup::exit(15);
+            })
+        });
+
+        // Filesystem events and anything else that wakes up the loop arrive on one channel
+        forward_events(fs_rx, tx.clone(), Message::Fs);
+        forward_events(polled_rx, tx.clone(), Message::Polled);
+
+        // Replaced when --config-reload picks up a change; a batch already scheduled runs
+        // with the arguments it was scheduled with
+        let mut a = Arc::new(self.args);
+        let mut config_text = a.config_file.as_ref().and_then(|f| std::fs::read_to_string(f).ok());
         // Need an endless loop here
         let mut loop_ix = 0_usize;
+        let mut watched_count = if a.heartbeat.is_some() {
+            watched_dirs(&a).len()
+        } else {
+            0
+        };
+        // To recognize the watched directory if it is renamed
+        let mut root_id = dir_id(&a.dir());
+        let mut last_event = Local::now();
+        let mut limit = RateLimit::new(a.max_events_per_second);
+        // Set once exit is requested, after which nothing more is collected
+        let mut draining = false;
         loop {
             trace!("Loop {}", loop_ix);
             loop_ix += 1;
-            match rx.recv() {
-                Ok(event) => {
+            let received = match a.heartbeat {
+                Some(heartbeat) => {
+                    match rx.recv_timeout(heartbeat.to_std().unwrap_or_default()) {
+                        Ok(message) => Ok(message),
+                        Err(RecvTimeoutError::Timeout) => {
+                            status_eprintln!(
+                                "Still watching {} dirs, idle {}s{}",
+                                watched_count,
+                                (Local::now() - last_event).num_seconds(),
+                                self.state.shared.backlog()
+                            );
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => Err(RecvError),
+                    }
+                }
+                None => rx.recv(),
+            };
+            last_event = Local::now();
+            if draining {
+                trace!("Exiting - ignore any more messages");
+                continue;
+            }
+            match received {
+                Ok(Message::Trigger) => {
+                    if self.state.shared.paused.swap(false, Ordering::SeqCst) {
+                        info!("Resumed from stdin");
+                        self.state = self.state.flush(&a);
+                    } else if a.stdin_trigger {
+                        info!("Run triggered from stdin");
+                        self.state = self.state.flush(&a);
+                    }
+                }
+                Ok(Message::ReplayDone) => {
+                    info!("All recorded events replayed - exiting once any batch has run");
+                    self.state.exit_when_idle(&a);
+                }
+                Ok(Message::DumpState) => self.state.dump(&a, &links),
+                Ok(Message::Drain) => {
+                    draining = true;
+                    self.state.drain(&a);
+                }
+                Ok(Message::Stalled) => {
+                    if let Some(w) = watchers.as_mut() {
+                        let why = "The file watcher stopped reporting changes";
+                        w.fall_back(why, &fs_tx, &a, &links);
+                    }
+                }
+                // The --poll-path watcher's events for those subtrees are the only ones used -
+                // the native watcher's may still arrive, and would run the command a second time
+                Ok(Message::Fs(event)) if is_polled(&a, watchers.as_ref(), &event) => {
+                    trace!("Ignore {:?} - polled with --poll-path", event.path);
+                }
+                Ok(Message::Fs(event) | Message::Polled(event)) => {
                     debug!("Change: {:?}", event);
+                    *self.state.shared.last_activity.lock().unwrap() = Local::now();
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder.record(&event);
+                    }
                     match event.op {
                         Ok(op) => {
-                            // There are a couple of events we don't care about:
-                            if !matches!(op, Op::CHMOD | Op::RESCAN) {
-                                if let Some(pth) = event.path {
-                                    // Test against the -f/--filter regex if there is one
-                                    if a.accepts(&pth) {
-                                        trace!("Filter regex accepts {:?}", &pth);
-                                        self.state = self.state.touch(pth, a);
-                                    } else {
-                                        debug!("Filter regex REJECTS path {:?}", &pth);
+                            if let (Some(grace), Some(w)) = (a.rewatch_grace, watchers.as_mut()) {
+                                if is_root_gone(&a, &event.path, op) {
+                                    rewatch(&mut w.backend, &a, &rx, grace);
+                                    // The contents may be entirely different now
+                                    self.state = self.state.touch(a.dir(), Op::CREATE, &a);
+                                    continue;
+                                }
+                            }
+                            if let Some(w) = watchers.as_mut().filter(|_| a.watch_list.is_empty()) {
+                                if is_root_gone(&a, &event.path, op) {
+                                    match find_root(&a, root_id) {
+                                        Root::Unchanged => {}
+                                        Root::Replaced => {
+                                            status_eprintln!(
+                                                "Watched directory {} was replaced - watching the new one",
+                                                a.path
+                                            );
+                                            w.replace(&a, &links, &fs_tx, &polled_tx, &tx);
+                                            root_id = dir_id(&a.dir());
+                                            self.state = self.state.touch(a.dir(), Op::CREATE, &a);
+                                        }
+                                        Root::MovedTo(dir) => {
+                                            status_eprintln!(
+                                                "Watched directory {} was renamed to {} - following it",
+                                                a.path,
+                                                dir.display()
+                                            );
+                                            a = Arc::new(a.moved_to(&dir));
+                                            links = discover_links(&a);
+                                            w.replace(&a, &links, &fs_tx, &polled_tx, &tx);
+                                        }
+                                        Root::Gone => {
+                                            status_eprintln!(
+                                                "Watched directory {} was removed or renamed, and its new location cannot be found - exiting (pass --rewatch-grace to wait for it to reappear)",
+                                                a.path
+                                            );
+                                            cleanup::exit(22);
+                                        }
+                                    }
+                                    continue;
+                                }
+                            }
+                            for pth in event.path.map(|p| links.translate(p)).unwrap_or_default() {
+                                if a.watches_dir_by_dir()
+                                    && (op.contains(Op::CREATE) || op.contains(Op::RENAME))
+                                {
+                                    if let Some(w) = watchers.as_mut() {
+                                        w.watch_new_dir(&pth, &a);
+                                    }
+                                }
+                                let probe = watchers.as_ref().and_then(|w| w.probe.as_ref());
+                                if let Some(probe) = probe.filter(|p| p.is_probe(&pth)) {
+                                    // Not a change - just proof the watcher still works
+                                    probe.seen();
+                                } else if a.flush_on.as_ref() == Some(&pth) {
+                                    // The sentinel is not itself a change to pass on, and
+                                    // touching it may produce several events
+                                    info!("Flush file {:?} changed - run now", pth);
+                                    self.state.schedule(Local::now(), Due::Flush, &a);
+                                } else if a.exit_on.as_ref() == Some(&pth) {
+                                    draining = true;
+                                    self.state.drain(&a);
+                                    break;
+                                } else if a.config_reload && a.config_file.as_ref() == Some(&pth) {
+                                    // Saving the file may produce several events, most of
+                                    // which find it unchanged, or empty while being rewritten
+                                    let text = std::fs::read_to_string(&pth).ok();
+                                    if let Some(new) = text.as_deref().filter(|t| {
+                                        !t.trim().is_empty() && Some(*t) != config_text.as_deref()
+                                    }) {
+                                        if let Some(args) = reload(&a, new) {
+                                            let w = watchers.as_mut();
+                                            if let Some(w) = w.filter(|_| args.watches_differently(&a)) {
+                                                status_eprintln!(
+                                                    "Configuration changed what is watched - restarting the watcher"
+                                                );
+                                                links = discover_links(&args);
+                                                w.replace(&args, &links, &fs_tx, &polled_tx, &tx);
+                                                if args.heartbeat.is_some() {
+                                                    watched_count = watched_dirs(&args).len();
+                                                }
+                                            }
+                                            a = args;
+                                        }
+                                        config_text = text;
                                     }
+                                } else if a.should_handle(&pth, op) && limit.admit() {
+                                    self.state = self.state.touch(pth, op, &a);
                                 }
                             }
                         }
                         Err(e) => {
                             error!("Error in watcher: {} for {:?}", e, event.path);
-                            if a.exit_on_error {
-                                eprintln!("exit-on-error is true - exiting");
-                                std::process::exit(10);
+                            if let Some(w) = watchers.as_mut().filter(|w| w.probe.is_some()) {
+                                let why = format!("The file watcher failed ({})", e);
+                                w.fall_back(&why, &fs_tx, &a, &links);
+                            } else if a.exit_on_error {
+                                status_eprintln!("exit-on-error is true - exiting");
+                                cleanup::exit(10);
                             }
                         }
                     }
                 }
                 Err(e) => {
-                    eprintln!("{}", e);
+                    status_eprintln!("{}", e);
                     if a.exit_on_error {
-                        eprintln!("exit-on-error is true - exiting");
-                        std::process::exit(11);
+                        status_eprintln!("exit-on-error is true - exiting");
+                        cleanup::exit(11);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Watch the tree, any symlink targets outside it, and the configuration file if it is to be
+/// reloaded
+fn watch_all(watcher: &mut Backend, args: &Args, links: &Links) {
+    for (dir, mode) in args.watch_roots() {
+        if args.watches_dir_by_dir() && mode == RecursiveMode::Recursive {
+            watch_dir_by_dir(watcher, &dir, args);
+        } else if let Err(e) = watcher.watch(&dir, mode) {
+            watch_failed(&format!("watch {}", dir.display()), &e);
+        }
+    }
+    for (dir, mode) in links.watch_dirs() {
+        if let Err(e) = watcher.watch(dir, *mode) {
+            status_eprintln!("Could not watch symlink target {}: {}", dir.display(), e);
+        }
+    }
+    if let Some(file) = args.config_file.as_ref().filter(|_| args.config_reload) {
+        watch_config(watcher, file, args);
+    }
+}
+
+/// With --one-filesystem or --prune-dir-glob, watch each directory in a tree which is to be
+/// watched separately, since a recursive watch would descend into other mounts and pruned
+/// directories
+fn watch_dir_by_dir(watcher: &mut Backend, root: &Path, args: &Args) {
+    let mut dirs = Vec::new();
+    collect_dirs(root, true, &descends(root, args), &mut dirs);
+    for (ix, dir) in dirs.iter().enumerate() {
+        match watcher.watch(dir, RecursiveMode::NonRecursive) {
+            Ok(_) => {}
+            Err(e) if ix == 0 => watch_failed(&format!("watch {}", dir.display()), &e),
+            // Probably deleted since it was listed
+            Err(e) => debug!("Could not watch {}: {}", dir.display(), e),
+        }
+    }
+}
+
+/// Start polling the --poll-path directories for changes
+fn poll_paths(args: &Args, tx: Sender<RawEvent>) -> Backend {
+    let mut poller =
+        Backend::poll(tx).unwrap_or_else(|e| watch_failed("start polling for changes", &e));
+    for dir in &args.poll_paths {
+        if let Err(e) = poller.watch(dir, args.recursion_mode()) {
+            watch_failed(&format!("poll {}", dir.display()), &e);
+        }
+    }
+    poller
+}
+
+/// The file watchers set up for a set of arguments - rebuilt together when
+/// --graceful-restart-on-config-change picks up a change to what is watched
+struct Watchers {
+    /// The native watcher, or the polling one --poll-fallback replaced it with
+    backend: Backend,
+    /// With --poll-path, the watcher polling those subtrees
+    poller: Option<Backend>,
+    /// With --poll-fallback, the check that the native watcher still reports changes
+    probe: Option<Probe>,
+}
+
+impl Watchers {
+    /// Watch everything `args` calls for, sending events to `fs_tx`, or to `polled_tx` for
+    /// --poll-path's
+    fn start(
+        args: &Args,
+        links: &Links,
+        fs_tx: &Sender<RawEvent>,
+        polled_tx: &Sender<RawEvent>,
+        tx: &Sender<Message>,
+    ) -> Self {
+        if let Some(max) = args.max_watchers {
+            let projected = watched_dirs(args).len() + links.watch_dirs().len();
+            if projected > max {
+                status_eprintln!(
+                    "Would watch {} directories, more than --max-watchers {} - watch a smaller tree",
+                    projected, max
+                );
+                cleanup::exit(19);
+            }
+        }
+        let mut backend = Backend::Native(
+            raw_watcher(fs_tx.clone())
+                .unwrap_or_else(|e| watch_failed("create a file watcher", &e)),
+        );
+        watch_all(&mut backend, args, links);
+        if args.print_watched_paths {
+            let mut dirs = watched_dirs(args);
+            dirs.extend(links.watch_dirs().iter().map(|(dir, _)| dir.clone()));
+            for dir in &dirs {
+                status_eprintln!("{}", dir.display());
+            }
+            for dir in &args.poll_paths {
+                status_eprintln!("{} (polled)", dir.display());
+            }
+            status_eprintln!("Watching {} directories", dirs.len());
+        }
+        let poller = if args.poll_paths.is_empty() {
+            None
+        } else {
+            Some(poll_paths(args, polled_tx.clone()))
+        };
+        let probe = args.poll_fallback.map(|interval| {
+            let stall_tx = tx.clone();
+            let (dir, _) = &args.watch_roots()[0];
+            Probe::start(dir, interval.to_std().unwrap_or_default(), move || {
+                let _ = stall_tx.send(Message::Stalled);
+            })
+        });
+        Watchers {
+            backend,
+            poller,
+            probe,
+        }
+    }
+
+    /// Tear down these watchers and start new ones for `args`
+    fn replace(
+        &mut self,
+        args: &Args,
+        links: &Links,
+        fs_tx: &Sender<RawEvent>,
+        polled_tx: &Sender<RawEvent>,
+        tx: &Sender<Message>,
+    ) {
+        if let Some(probe) = &self.probe {
+            probe.stop();
+        }
+        // Dropping the old watchers stops them
+        *self = Watchers::start(args, links, fs_tx, polled_tx, tx);
+    }
+
+    /// With --one-filesystem or --prune-dir-glob, watch a directory created or moved into the
+    /// tree, and any beneath it, which the watcher does not do by itself for directories
+    /// watched one by one
+    fn watch_new_dir(&mut self, path: &Path, args: &Args) {
+        if !path.is_dir() {
+            return;
+        }
+        let roots = args.watch_roots();
+        let Some((root, _)) = roots
+            .iter()
+            .find(|(root, mode)| *mode == RecursiveMode::Recursive && path.starts_with(root))
+        else {
+            return;
+        };
+        let descend = descends(root, args);
+        if !descend(path) {
+            return;
+        }
+        let mut dirs = Vec::new();
+        collect_dirs(path, true, &descend, &mut dirs);
+        for dir in &dirs {
+            if let Err(e) = self.backend.watch(dir, RecursiveMode::NonRecursive) {
+                debug!("Could not watch {}: {}", dir.display(), e);
+            }
+        }
+    }
+
+    /// With --poll-fallback, replace the native watcher with a polling one
+    fn fall_back(&mut self, why: &str, tx: &Sender<RawEvent>, args: &Args, links: &Links) {
+        let Some(probe) = self.probe.as_ref().filter(|_| !self.backend.is_polling()) else {
+            return;
+        };
+        status_eprintln!("{} - switching to polling for changes", why);
+        match Backend::poll(tx.clone()) {
+            Ok(mut poll) => {
+                watch_all(&mut poll, args, links);
+                self.backend = poll;
+                probe.stop();
+            }
+            Err(e) => status_eprintln!("Could not start polling for changes: {}", e),
+        }
+    }
+}
+
+/// The symlinks to follow out of the tree, with --resolve-symlink-targets
+fn discover_links(args: &Args) -> Links {
+    if args.resolve_symlink_targets {
+        Links::discover(args)
+    } else {
+        Links::default()
+    }
+}
+
+/// Whether an event from the native watcher is for a subtree the --poll-path watcher covers
+fn is_polled(args: &Args, watchers: Option<&Watchers>, event: &RawEvent) -> bool {
+    match (watchers.and_then(|w| w.poller.as_ref()), &event.path) {
+        (Some(_), Some(path)) => args.poll_paths.iter().any(|dir| path.starts_with(dir)),
+        _ => false,
+    }
+}
+
+/// Watch the directory of the configuration file for --config-reload, unless watching the tree
+/// already covers it
+fn watch_config(watcher: &mut Backend, file: &Path, args: &Args) {
+    let Some(dir) = file.parent() else {
+        return;
+    };
+    let covered = args.watch_roots().iter().any(|(root, mode)| match mode {
+        RecursiveMode::Recursive => dir.starts_with(root),
+        RecursiveMode::NonRecursive => dir == root,
+    });
+    if !covered {
+        if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+            status_eprintln!("Could not watch {} for changes: {}", file.display(), e);
+        }
+    }
+}
+
+/// Apply a change to the configuration file, keeping the current arguments if it is invalid
+fn reload(args: &Args, text: &str) -> Option<Arc<Args>> {
+    match args.reload(text) {
+        // A partly written file may parse, so only report when the result is different
+        Ok(fresh) if fresh.to_string() == args.to_string() => {
+            debug!("Reloaded configuration changes nothing");
+            None
+        }
+        Ok(fresh) => {
+            status_eprintln!("Configuration reloaded");
+            debug!("Reloaded args: {:?}", fresh);
+            Some(Arc::new(fresh))
+        }
+        Err(e) => {
+            status_eprintln!("Ignoring invalid configuration: {}", e);
+            None
+        }
+    }
+}
+
+/// Explain why the watcher could not be set up, and exit
+fn watch_failed(what: &str, e: &notify::Error) -> ! {
+    status_eprintln!("Could not {}: {}", what, e);
+    if let Some(hint) = limit_hint(e) {
+        status_eprintln!("{}", hint);
+    }
+    cleanup::exit(19);
+}
+
+/// If the error is from hitting a kernel limit on watches, how to raise it
+#[cfg(target_os = "linux")]
+fn limit_hint(e: &notify::Error) -> Option<String> {
+    let notify::Error::Io(io) = e else {
+        return None;
+    };
+    let setting = match io.raw_os_error()? {
+        libc::ENOSPC => "max_user_watches",
+        libc::EMFILE => "max_user_instances",
+        _ => return None,
+    };
+    let current = std::fs::read_to_string(format!("/proc/sys/fs/inotify/{}", setting))
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+    Some(format!(
+        "The limit fs.inotify.{} (currently {}) was reached.  Raise it, e.g. with\n  \
+         sudo sysctl fs.inotify.{}={}\n\
+         (add it to /etc/sysctl.conf to keep it), or watch fewer directories.",
+        setting,
+        current,
+        setting,
+        current.parse::<u64>().map(|n| n * 4).unwrap_or(524288)
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn limit_hint(_e: &notify::Error) -> Option<String> {
+    None
+}
+
+/// List the directories being watched, for reporting
+fn watched_dirs(args: &Args) -> Vec<PathBuf> {
+    let mut result = Vec::new();
+    for (dir, mode) in args.watch_roots() {
+        let recursive = mode == RecursiveMode::Recursive;
+        collect_dirs(&dir, recursive, &descends(&dir, args), &mut result);
+    }
+    result
+}
+
+/// Whether a directory beneath `root` is watched along with it - not if it is on another
+/// filesystem, with --one-filesystem, or pruned with --prune-dir-glob
+fn descends<'a>(root: &Path, args: &'a Args) -> impl Fn(&Path) -> bool + 'a {
+    let device = device_of(root).filter(|_| args.one_filesystem);
+    move |dir| (device.is_none() || device_of(dir) == device) && !args.is_pruned(dir)
+}
+
+/// Collect a directory and, if recursive, those beneath it which `descend` accepts
+fn collect_dirs(
+    dir: &Path,
+    recursive: bool,
+    descend: &dyn Fn(&Path) -> bool,
+    into: &mut Vec<PathBuf>,
+) {
+    into.push(dir.to_path_buf());
+    if recursive {
+        if let Ok(entries) = std::fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                // Do not follow symlinks, as the watcher does not
+                let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                if is_dir && descend(&entry.path()) {
+                    collect_dirs(&entry.path(), recursive, descend, into);
+                }
+            }
+        }
+    }
+}
+
+/// With --max-events-per-second, counts events in one-second windows, dropping those over
+/// the limit so a runaway producer cannot grow the pending set without bound
+struct RateLimit {
+    max: Option<usize>,
+    window_start: DateTime<Local>,
+    admitted: usize,
+    dropped: usize,
+}
+
+impl RateLimit {
+    fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            window_start: Local::now(),
+            admitted: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Whether another event may be accepted now
+    fn admit(&mut self) -> bool {
+        let Some(max) = self.max else {
+            return true;
+        };
+        let now = Local::now();
+        if now - self.window_start >= chrono::Duration::seconds(1) {
+            if self.dropped > 0 {
+                status_eprintln!(
+                    "Dropped {} events over the limit of {} per second",
+                    self.dropped, max
+                );
+            }
+            self.window_start = now;
+            self.admitted = 0;
+            self.dropped = 0;
+        }
+        if self.admitted < max {
+            self.admitted += 1;
+            true
+        } else {
+            self.dropped += 1;
+            trace!("Over event rate limit - dropped {}", self.dropped);
+            false
+        }
+    }
+}
+
+/// Determine if an event means the watched directory itself was deleted or renamed away
+fn is_root_gone(args: &Args, path: &Option<PathBuf>, op: Op) -> bool {
+    match path {
+        Some(path) => {
+            path.as_path() == args.dir().as_path() && op.intersects(Op::REMOVE | Op::RENAME)
+        }
+        None => false,
+    }
+}
+
+/// What became of the watched directory after a removal or rename was reported for it
+enum Root {
+    /// It is still there - the event was for something else of the same name
+    Unchanged,
+    /// A different directory is now at its path
+    Replaced,
+    /// It was renamed to this path, within the same parent directory
+    MovedTo(PathBuf),
+    /// It was removed, or moved somewhere it cannot be found
+    Gone,
+}
+
+/// Find the watched directory after a removal or rename was reported for it, by looking for
+/// the directory `id` identifies beside its old path - on unix; elsewhere, a directory which
+/// was renamed away cannot be told apart from one which was removed
+fn find_root(args: &Args, id: Option<(u64, u64)>) -> Root {
+    let root = args.dir();
+    if root.is_dir() {
+        return match (id, dir_id(&root)) {
+            (Some(old), Some(now)) if old == now => Root::Unchanged,
+            _ => Root::Replaced,
+        };
+    }
+    let (Some(id), Some(parent)) = (id, root.parent()) else {
+        return Root::Gone;
+    };
+    let found = std::fs::read_dir(parent).ok().and_then(|entries| {
+        entries
+            .flatten()
+            .map(|entry| entry.path())
+            .find(|path| path.is_dir() && dir_id(path) == Some(id))
+    });
+    match found {
+        Some(dir) => Root::MovedTo(dir),
+        None => Root::Gone,
+    }
+}
+
+/// The device and inode of a directory, which survive renaming it
+#[cfg(unix)]
+fn dir_id(dir: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(dir).ok().map(|meta| (meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn dir_id(_dir: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// Re-establish the watch on the watched directory after it has been replaced (e.g. by
+/// renaming a new directory into place during a deploy), waiting for the grace period
+/// first so the flurry of events from the swap is discarded rather than reacted to
+fn rewatch(
+    watcher: &mut Backend,
+    args: &Args,
+    rx: &Receiver<Message>,
+    grace: chrono::Duration,
+) {
+    info!(
+        "Watched directory {} was removed or renamed - re-watching in {}ms",
+        args.path,
+        grace.num_milliseconds()
+    );
+    if let Err(e) = watcher.unwatch(&args.dir()) {
+        debug!("Unwatch of {} failed: {}", args.path, e);
+    }
+    loop {
+        std::thread::sleep(grace.to_std().unwrap_or_default());
+        let mut discarded = 0_usize;
+        while rx.try_recv().is_ok() {
+            discarded += 1;
+        }
+        debug!("Discarded {} events received during grace period", discarded);
+        match watcher.watch(&args.dir(), args.recursion_mode()) {
+            Ok(_) => {
+                info!("Re-established watch on {}", args.path);
+                return;
+            }
+            Err(e) => {
+                if args.verbose {
+                    status_eprintln!("Cannot watch {} yet ({}) - will retry", args.path, e);
+                }
+                debug!("Could not re-watch {}: {}", args.path, e);
+            }
+        }
+    }
+}
+
+/// Things which can wake up the main loop
+enum Message {
+    /// A raw event from the file watcher
+    Fs(RawEvent),
+    /// A request to run the command now, from stdin
+    Trigger,
+    /// All events passed to --replay-events have been sent
+    ReplayDone,
+    /// A request to describe what watchfs is doing, from SIGUSR1
+    DumpState,
+    /// With --poll-fallback, the watcher failed to report a probe file
+    Stalled,
+    /// A raw event from the --poll-path watcher
+    Polled(RawEvent),
+    /// A request to run any pending changes and exit, from SIGUSR2 (--exit-on sends none, its
+    /// file being noticed in the loop)
+    Drain,
+}
+
+/// Pass events from a file watcher through to the main loop's channel, as `message`
+fn forward_events(fs_rx: Receiver<RawEvent>, tx: Sender<Message>, message: fn(RawEvent) -> Message) {
+    std::thread::spawn(move || {
+        for event in fs_rx {
+            if tx.send(message(event)).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Read lines from stdin on a background thread, sending a trigger to every rule for each
+/// empty line - or any line to the first rule whose --confirm waits for an answer
+fn read_triggers(mut txs: Vec<Sender<Message>>, shared: Vec<Arc<Shared>>) {
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let answer = shared.iter().find_map(|s| s.answer.lock().unwrap().clone());
+            if let (Ok(line), Some(answer)) = (&line, answer) {
+                let _ = answer.send(line.clone());
+                continue;
+            }
+            match line {
+                Ok(line) if line.trim().is_empty() => {
+                    txs.retain(|tx| tx.send(Message::Trigger).is_ok());
+                    if txs.is_empty() {
+                        break;
                     }
                 }
+                Ok(line) => debug!("Ignoring non-empty line on stdin: '{}'", line),
+                Err(e) => {
+                    error!("Error reading stdin: {}", e);
+                    break;
+                }
             }
         }
+        debug!("Stdin closed - no more triggers.");
+    });
+}
+
+/// On unix, print the state of every rule's watcher on SIGUSR1, for diagnosing one which
+/// appears stuck, and on SIGUSR2 run any pending changes and exit, as --exit-on does
+#[cfg(unix)]
+fn handle_signals(mut txs: Vec<Sender<Message>>) {
+    use signal_hook::consts::{SIGUSR1, SIGUSR2};
+    use signal_hook::iterator::Signals;
+    match Signals::new([SIGUSR1, SIGUSR2]) {
+        Ok(mut signals) => {
+            std::thread::spawn(move || {
+                for signal in signals.forever() {
+                    let message = || {
+                        if signal == SIGUSR2 {
+                            Message::Drain
+                        } else {
+                            Message::DumpState
+                        }
+                    };
+                    txs.retain(|tx| tx.send(message()).is_ok());
+                    if txs.is_empty() {
+                        break;
+                    }
+                }
+            });
+        }
+        Err(e) => error!("Could not install SIGUSR1 and SIGUSR2 handlers: {}", e),
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_signals(_txs: Vec<Sender<Message>>) {}
+
+/// Accumulates changed paths between runs of the command, along with every kind of
+/// change seen for each - either sorted, or in the order in which they were first changed
+enum PathSet {
+    Sorted(BTreeMap<String, Op>),
+    Ordered(IndexMap<String, Op>),
+}
+
+impl PathSet {
+    fn new(preserve_order: bool) -> Self {
+        if preserve_order {
+            PathSet::Ordered(IndexMap::new())
+        } else {
+            PathSet::Sorted(BTreeMap::new())
+        }
+    }
+
+    fn insert(&mut self, path: String, op: Op) {
+        let ops = match self {
+            PathSet::Sorted(map) => map.entry(path).or_insert(Op::empty()),
+            PathSet::Ordered(map) => map.entry(path).or_insert(Op::empty()),
+        };
+        ops.insert(op);
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            PathSet::Sorted(map) => map.is_empty(),
+            PathSet::Ordered(map) => map.is_empty(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            PathSet::Sorted(map) => map.len(),
+            PathSet::Ordered(map) => map.len(),
+        }
+    }
+
+    /// The paths in this set, in emit order
+    fn paths(&self) -> Vec<&str> {
+        match self {
+            PathSet::Sorted(map) => map.keys().map(String::as_str).collect(),
+            PathSet::Ordered(map) => map.keys().map(String::as_str).collect(),
+        }
+    }
+
+    /// Removes and returns the contents of this set, in emit order
+    fn take(&mut self) -> Vec<(String, Op)> {
+        match self {
+            PathSet::Sorted(map) => std::mem::take(map).into_iter().collect(),
+            PathSet::Ordered(map) => std::mem::take(map).into_iter().collect(),
+        }
+    }
+}
+
+/// State shared between the thread receiving events and the timer thread which runs the command
+struct Shared {
+    paths: Mutex<PathSet>,
+    /// When a batch was last emitted, if one has been
+    last_run: Mutex<Option<DateTime<Local>>>,
+    /// When the command last exited unsuccessfully, if it has
+    last_failure: Mutex<Option<DateTime<Local>>>,
+    /// When the watcher last reported anything, whether or not it counted as a change
+    last_activity: Mutex<DateTime<Local>>,
+    /// With --pause-on-error, set when the command fails and cleared from stdin;
+    /// while set, changes accumulate but the command is not run
+    paused: AtomicBool,
+    /// Set while the command runs
+    running: AtomicBool,
+    /// Set when changes arrive while the command runs, so they will be run as one batch
+    overlapped: AtomicBool,
+    /// Where each batch goes - the command, and any JSON outputs
+    sinks: Vec<Sink>,
+    /// With --confirm, where lines from stdin go while an answer is awaited
+    answer: Mutex<Option<Sender<String>>>,
+    /// With --show-timing, when the first change in the pending batch arrived, and how many
+    /// changes since have pushed back its deadline
+    batch_timing: Mutex<Option<(DateTime<Local>, usize)>>,
+    /// With --skip-duplicate-batches, the sorted paths of the last successful run, and when
+    last_batch: Mutex<Option<(Vec<String>, DateTime<Local>)>>,
+    /// Set once this rule has drained or finished its replay, and is only waiting on the others
+    finished: AtomicBool,
+}
+
+impl Shared {
+    /// Note that this rule is done, exiting successfully if every other rule is too
+    fn finish(&self) {
+        if !self.finished.swap(true, Ordering::SeqCst)
+            && RULES_LEFT.fetch_sub(1, Ordering::SeqCst) <= 1
+        {
+            cleanup::exit(0);
+        }
+    }
+
+    /// With --skip-duplicate-batches, whether a batch has the same paths as the last successful
+    /// run, and it was less than `window` ago
+    fn is_duplicate(&self, paths: &[String], window: chrono::Duration) -> bool {
+        let last = self.last_batch.lock().unwrap();
+        let Some((last_paths, when)) = last.as_ref() else {
+            return false;
+        };
+        let mut sorted = paths.to_vec();
+        sorted.sort();
+        let ago = Local::now() - *when;
+        debug!("Last run {}ms ago", ago.num_milliseconds());
+        ago < window && sorted == *last_paths
+    }
+
+    /// While the command runs, a description of the changes waiting for the next run
+    fn backlog(&self) -> String {
+        if !self.running.load(Ordering::SeqCst) {
+            return String::new();
+        }
+        match self.paths.lock().unwrap().len() {
+            0 => String::from(", command running"),
+            n => format!(", command running with {} changed paths waiting", n),
+        }
+    }
+
+    /// If a failure cooldown is set and the command failed recently, the time remaining
+    /// before it may be run again
+    fn cooldown_remaining(&self, args: &Args) -> Option<chrono::Duration> {
+        let cooldown = args.failure_cooldown?;
+        let failed_at = (*self.last_failure.lock().unwrap())?;
+        let remaining = (failed_at + cooldown) - Local::now();
+        if remaining > chrono::Duration::zero() {
+            Some(remaining)
+        } else {
+            None
+        }
     }
 }
 
 struct WatchState {
     timer: Timer,
     guard: Option<Guard>,
-    paths: Arc<Mutex<BTreeSet<String>>>,
+    /// Guard for the timer which exits the process once a replay has been processed
+    exit_guard: Option<Guard>,
+    /// Source of --jitter offsets
+    rng: StdRng,
+    /// The delay for the batch of changes being collected
+    batch_delay: chrono::Duration,
+    /// How many batches of changes have started collecting - with --fast-first, the first
+    /// gets the shorter delay
+    batches_started: usize,
+    /// With --settle-rate, when the changes in the last second of the batch being collected
+    /// arrived
+    arrivals: VecDeque<DateTime<Local>>,
+    /// With --settle-rate, when the batch being collected began
+    batch_began: DateTime<Local>,
+    /// When the command is next due to run
+    deadline: Option<DateTime<Local>>,
+    shared: Arc<Shared>,
 }
 
 impl WatchState {
-    fn touch(mut self, path: PathBuf, args: &'static Args) -> Self {
+    fn touch(mut self, path: PathBuf, op: Op, args: &Arc<Args>) -> Self {
         trace!("Touch path {:?}", path);
         if let Some(s) = path.to_str() {
-            let deadline: DateTime<Local> = Local::now() + args.delay();
-
-            let mut set = self.paths.lock().unwrap();
-            set.insert(s.to_string());
+            let mut set = self.shared.paths.lock().unwrap();
+            // With --debounce-for, the longest delay for any path in a batch governs it; an
+            // empty set means the last batch was taken, and this change starts a new one
+            let starts_batch = set.is_empty();
+            if starts_batch {
+                self.batches_started += 1;
+            }
+            let mut delay = args.delay_for(&path);
+            if let Some(fast) = args.fast_first.filter(|_| self.batches_started == 1) {
+                delay = delay.min(fast);
+            }
+            if set.is_empty() || delay > self.batch_delay {
+                self.batch_delay = delay;
+            }
+            if args.show_timing {
+                // Updated under the paths' lock, so it always describes the batch they make up
+                let mut timing = self.shared.batch_timing.lock().unwrap();
+                match timing.as_mut() {
+                    Some((_, resets)) if !set.is_empty() => *resets += 1,
+                    _ => *timing = Some((Local::now(), 0)),
+                }
+            }
+            set.insert(s.to_string(), op);
+            let size = set.len();
             drop(set);
+            if self.shared.running.load(Ordering::SeqCst) {
+                // The command cannot keep up; this batch waits for it, and collects meanwhile
+                self.shared.overlapped.store(true, Ordering::SeqCst);
+            }
+            if let Some(limit) = args.working_set_limit.filter(|limit| size >= *limit) {
+                if size == limit && args.verbose {
+                    status_eprintln!("{} changed paths collected - running now", size);
+                }
+                self.schedule(Local::now(), Due::Flush, args);
+                return self;
+            }
+
+            let mut deadline: DateTime<Local> = Local::now() + self.batch_delay;
+            if let Some(rate) = args.settle_rate {
+                deadline = deadline.min(self.settled_at(rate, starts_batch));
+            }
+            if let Some(jitter) = args.jitter {
+                // Stagger runs of many instances reacting to the same change
+                deadline += chrono::Duration::milliseconds(
+                    self.rng.gen_range(0..=jitter.num_milliseconds()),
+                );
+            }
 
-            let mux = self.paths.clone();
+            if let Some(hours) = args
+                .active_hours
+                .filter(|_| !args.drop_outside_active_hours)
+            {
+                // Outside the window, wake when it opens rather than waiting for another change
+                deadline = hours.next_open(deadline);
+            }
 
             trace!("New deadline is {}", deadline);
 
-            let new_guard = self.timer.schedule(deadline, None, move || {
-                debug!("Timer tick.");
-                emit(&mux, args);
-            });
+            self.schedule(deadline, Due::Quiet, args);
+        }
+        self
+    }
 
-            if let Some(old) = self.guard.replace(new_guard) {
-                trace!("Drop old timer guard");
-                drop(old)
-            } else {
-                trace!("No existing timer");
-            }
+    /// With --settle-rate, note a change arriving, and return when, if no more arrive, fewer
+    /// than `rate` will have arrived in the second before - but no sooner than a second after
+    /// the batch began, so its rate has been measured over a whole second
+    fn settled_at(&mut self, rate: usize, starts_batch: bool) -> DateTime<Local> {
+        let now = Local::now();
+        let window = chrono::Duration::seconds(1);
+        if starts_batch {
+            self.arrivals.clear();
+            self.batch_began = now;
+        }
+        self.arrivals.push_back(now);
+        while self.arrivals.front().is_some_and(|at| *at + window <= now) {
+            self.arrivals.pop_front();
         }
+        // The count drops below the rate once all but the last rate - 1 arrivals have left
+        let settled = match self.arrivals.len().checked_sub(rate) {
+            Some(ix) => self.arrivals[ix] + window,
+            None => now,
+        };
+        settled.max(self.batch_began + window)
+    }
+
+    /// Run the command as soon as possible, with whatever paths have accumulated (if any)
+    fn flush(mut self, args: &Arc<Args>) -> Self {
+        self.schedule(Local::now(), Due::Force, args);
         self
     }
+
+    /// Exit after any pending batch has been run - the timer runs callbacks one at a time in
+    /// deadline order, so one scheduled later than any pending batch runs once it is done
+    fn exit_when_idle(&mut self, args: &Args) {
+        let mut deadline = Local::now() + args.longest_delay() + chrono::Duration::milliseconds(1);
+        if let Some(jitter) = args.jitter {
+            deadline += jitter;
+        }
+        let shared = self.shared.clone();
+        self.exit_guard = Some(self.timer.schedule(deadline, None, move || shared.finish()));
+    }
+
+    /// Run any pending batch now, then - once it and any commands still running are done -
+    /// exit successfully; the timer runs callbacks one at a time in deadline order, so one
+    /// scheduled just after the batch runs once it is done
+    fn drain(&mut self, args: &Arc<Args>) {
+        status_eprintln!("Exit requested - running any pending changes, then exiting");
+        self.schedule(Local::now(), Due::Flush, args);
+        let deadline = Local::now() + chrono::Duration::milliseconds(1);
+        let (args, shared) = (args.clone(), self.shared.clone());
+        self.exit_guard = Some(self.timer.schedule(deadline, None, move || {
+            args.finish_running();
+            shared.finish();
+        }));
+    }
+
+    /// Describe the pending batch, watched directories and recent runs on stderr
+    fn dump(&self, args: &Args, links: &Links) {
+        let now = Local::now();
+        let paths = self.shared.paths.lock().unwrap().paths().join("\n  ");
+        let paths = if paths.is_empty() {
+            "none"
+        } else {
+            paths.as_str()
+        };
+        let mut dirs = watched_dirs(args);
+        dirs.extend(links.watch_dirs().iter().map(|(dir, _)| dir.clone()));
+        let when = |time: Option<DateTime<Local>>| match time {
+            Some(time) => format!("{} ({}ms ago)", time, (now - time).num_milliseconds()),
+            None => String::from("never"),
+        };
+        let deadline = match self.deadline {
+            Some(deadline) if deadline > now => {
+                format!("{} (in {}ms)", deadline, (deadline - now).num_milliseconds())
+            }
+            _ => String::from("none"),
+        };
+        status_eprintln!(
+            "---- watchfs state ----\nPending paths:\n  {}\nNext run: {}\nLast run: {}\nLast failure: {}\nLast activity: {}\nPaused: {}\nCommand running: {}\nWatching {} directories:\n  {}\n-----------------------",
+            paths,
+            deadline,
+            when(*self.shared.last_run.lock().unwrap()),
+            when(*self.shared.last_failure.lock().unwrap()),
+            when(Some(*self.shared.last_activity.lock().unwrap())),
+            self.shared.paused.load(Ordering::SeqCst),
+            self.shared.running.load(Ordering::SeqCst),
+            dirs.len(),
+            dirs.iter().map(|d| d.to_string_lossy()).collect::<Vec<_>>().join("\n  ")
+        );
+    }
+
+    fn schedule(&mut self, deadline: DateTime<Local>, due: Due, args: &Arc<Args>) {
+        self.deadline = Some(deadline);
+        let shared = self.shared.clone();
+        let args = args.clone();
+        let new_guard = self.timer.schedule(deadline, None, move || {
+            debug!("Timer tick.");
+            emit(&shared, &args, due);
+        });
+
+        if let Some(old) = self.guard.replace(new_guard) {
+            trace!("Drop old timer guard");
+            drop(old)
+        } else {
+            trace!("No existing timer");
+        }
+    }
 }
 
-fn emit(mux: &Arc<Mutex<BTreeSet<String>>>, args: &Args) {
-    let mut set = mux.lock().unwrap();
-    let copy = set.clone();
-    set.clear();
+/// Why a batch is being run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Due {
+    /// The delay since the last change has passed
+    Quiet,
+    /// Something asked for the changes so far to be run now - --flush-on or
+    /// --working-set-limit - if there are any
+    Flush,
+    /// A run was requested from stdin, which happens even if nothing has changed
+    Force,
+}
+
+/// Run the command with the accumulated paths
+fn emit(shared: &Shared, args: &Args, due: Due) {
+    if let Some(remaining) = shared.cooldown_remaining(args) {
+        // Blocking the timer thread here means changes which arrive during the cooldown
+        // are collected into a single batch run once it has elapsed
+        info!(
+            "Command failed recently - waiting {}ms for failure cooldown",
+            remaining.num_milliseconds()
+        );
+        std::thread::sleep(remaining.to_std().unwrap_or_default());
+    }
+    if let Some(quiet) = args.after_quiet_only {
+        // Filtered-out events still mean the filesystem is busy - e.g. a slow network
+        // mount still delivering a large copy - so wait until it has been quiet for a while
+        loop {
+            let idle = Local::now() - *shared.last_activity.lock().unwrap();
+            if idle >= quiet {
+                break;
+            }
+            debug!("Events still arriving - wait {}ms", (quiet - idle).num_milliseconds());
+            std::thread::sleep((quiet - idle).to_std().unwrap_or_default());
+        }
+    }
+    if let Some(window) = args.stable_for {
+        wait_until_stable(shared, window, args.stable_poll());
+    }
+    if shared.paused.load(Ordering::SeqCst) {
+        debug!("Paused after failure - not running command");
+        return;
+    }
+    let now = Local::now();
+    if let Some(hours) = args
+        .active_hours
+        .filter(|h| due == Due::Quiet && !h.contains(now))
+    {
+        if args.drop_outside_active_hours {
+            let dropped = shared.paths.lock().unwrap().take();
+            shared.batch_timing.lock().unwrap().take();
+            if args.verbose {
+                status_println!(
+                    "Outside --active-hours {} - dropping {} changed paths",
+                    hours,
+                    dropped.len()
+                );
+            }
+        } else if args.verbose {
+            status_println!(
+                "Outside --active-hours {} - keeping changes until {}",
+                hours,
+                hours.next_open(now)
+            );
+        }
+        return;
+    }
+    let mut set = shared.paths.lock().unwrap();
+    let mut copy = set.take();
+    let timing = shared.batch_timing.lock().unwrap().take();
     drop(set);
+    // With --min-paths, to put back if there are too few
+    let taken = args.min_paths.map(|_| copy.clone());
+    if args.existing_only || args.deletions_only {
+        // Whether a path exists now is what matters, whatever happened to it in the batch
+        copy.retain(|(path, _)| Path::new(path).exists() == args.existing_only);
+    }
+    if args.canonical_paths {
+        copy = canonical(copy, args);
+    }
+    if args.by_dir {
+        copy = by_dir(copy, args);
+    }
 
-    if copy.is_empty() {
+    if copy.is_empty() && due != Due::Force && !args.emit_empty {
         debug!("No changed paths remain in set - already published?");
         return;
     }
+    if let Some(min) = args.min_paths.filter(|min| due == Due::Quiet && copy.len() < *min) {
+        if args.verbose {
+            status_println!(
+                "{} changed paths, fewer than --min-paths {} - {}",
+                copy.len(),
+                min,
+                if args.drop_below_min_paths {
+                    "dropping them"
+                } else {
+                    "waiting for more"
+                }
+            );
+        }
+        if !args.drop_below_min_paths {
+            let mut set = shared.paths.lock().unwrap();
+            for (path, op) in taken.unwrap_or_default() {
+                set.insert(path, op);
+            }
+            let mut pending = shared.batch_timing.lock().unwrap();
+            if pending.is_none() {
+                *pending = timing;
+            }
+        }
+        return;
+    }
 
+    *shared.last_run.lock().unwrap() = Some(Local::now());
+    if let Some((first, resets)) = timing {
+        status_eprintln!(
+            "Batch of {} changed paths waited {}ms since its first change, pushed back by {} later changes",
+            copy.len(),
+            (Local::now() - first).num_milliseconds(),
+            resets
+        );
+    }
     if args.verbose {
-        println!("EMIT {:?}", copy);
+        if shared.overlapped.swap(false, Ordering::SeqCst) {
+            status_println!(
+                "Coalesced {} changed paths which arrived while the command was running",
+                copy.len()
+            );
+        }
+        status_println!("EMIT {:?}", copy);
     }
 
     debug!("Emit {} changed paths: {:?}", copy.len(), copy);
 
     let mut v = Vec::with_capacity(copy.len());
-    for p in copy {
+    let mut ops = Vec::with_capacity(copy.len());
+    let mut changes = Vec::with_capacity(copy.len());
+    let mut sources = Vec::with_capacity(copy.len());
+    let roots = args.relative_roots();
+    for (p, op) in copy {
+        changes.push(Change::of(op, Path::new(&p).exists()));
+        sources.push(PathBuf::from(&p));
         if args.relativize_paths {
             let buf = PathBuf::from(p);
-            let dir = args.dir();
-            v.push(relativize(dir, buf).to_str().unwrap().to_string());
+            v.push(args.slashed(relativize(&roots, buf).to_str().unwrap().to_string()));
         } else {
-            v.push(p);
+            v.push(args.slashed(p));
+        }
+        ops.push(op);
+    }
+    let batch = Batch::new(args.path.as_str(), v, ops, changes, sources);
+    for sink in &shared.sinks {
+        match sink {
+            Sink::Command => run_batch(shared, args, &batch.paths, &batch.changes),
+            sink => sink.send(&batch),
         }
     }
-    args.run_command(&v);
 }
 
-fn relativize(base: PathBuf, target: PathBuf) -> PathBuf {
-    Path::strip_prefix(target.as_path(), base.as_path())
-        .unwrap_or_else(|_| panic!("Path not relative: {:?} and {:?}", base, target))
-        .to_path_buf()
+/// With --stable-for, wait until none of the changed files has changed size or modification
+/// time for `window`, looking at them every `poll` - a path changed or added to the batch
+/// meanwhile starts the wait over
+fn wait_until_stable(shared: &Shared, window: chrono::Duration, poll: chrono::Duration) {
+    let stat = |path: &str| {
+        std::fs::metadata(path)
+            .ok()
+            .map(|meta| (meta.len(), meta.modified().ok()))
+    };
+    let mut last = Vec::new();
+    let mut since = Local::now();
+    loop {
+        let paths: Vec<String> = {
+            let set = shared.paths.lock().unwrap();
+            set.paths().into_iter().map(String::from).collect()
+        };
+        let stats: Vec<_> = paths.into_iter().map(|path| (stat(&path), path)).collect();
+        if stats != last {
+            last = stats;
+            since = Local::now();
+        }
+        let stable = Local::now() - since;
+        if stable >= window {
+            break;
+        }
+        debug!("Changed files stable for {}ms", stable.num_milliseconds());
+        std::thread::sleep(poll.min(window - stable).to_std().unwrap_or_default());
+    }
+}
+
+/// Run the command on a batch, unless it repeats the last one or --confirm is refused, and
+/// note how it went
+fn run_batch(shared: &Shared, args: &Args, v: &[String], changes: &[Change]) {
+    if let Some(window) = args.skip_duplicate_batches {
+        if shared.is_duplicate(v, window) {
+            if args.verbose {
+                status_println!("Skipping a batch of the same paths as the last run");
+            }
+            return;
+        }
+    }
+    if args.confirm && args.has_command() && !confirm(shared, args, v) {
+        return;
+    }
+    if args.announce && args.has_command() {
+        announce(args, v);
+    }
+    shared.running.store(true, Ordering::SeqCst);
+    let succeeded = args.run_for_changes(v, changes);
+    shared.running.store(false, Ordering::SeqCst);
+    if succeeded && args.skip_duplicate_batches.is_some() {
+        let mut sorted = v.to_vec();
+        sorted.sort();
+        *shared.last_batch.lock().unwrap() = Some((sorted, Local::now()));
+    }
+    if !succeeded {
+        *shared.last_failure.lock().unwrap() = Some(Local::now());
+        if args.pause_on_error {
+            shared.paused.store(true, Ordering::SeqCst);
+            status_eprintln!("Command failed - paused.  Press Enter to resume.");
+        }
+    }
+}
+
+/// With --announce, summarize the run about to start in one line, e.g.
+/// `> running cargo build for 3 changed files (src/a.rs, src/b.rs, +1 more)`
+fn announce(args: &Args, paths: &[String]) {
+    // Enough to recognize a batch by, without wrapping a typical terminal line
+    const SHOWN: usize = 2;
+    const SHOWN_CHARS: usize = 60;
+    let mut listed = String::new();
+    let mut shown = 0;
+    for path in paths.iter().take(SHOWN) {
+        if shown > 0 && listed.len() + path.len() > SHOWN_CHARS {
+            break;
+        }
+        if shown > 0 {
+            listed.push_str(", ");
+        }
+        listed.push_str(path);
+        shown += 1;
+    }
+    if shown < paths.len() {
+        listed.push_str(&format!(", +{} more", paths.len() - shown));
+    }
+    let line = match paths.len() {
+        0 => format!("> running {}", args.describe_command()),
+        1 => format!("> running {} for 1 changed file ({})", args.describe_command(), listed),
+        n => format!("> running {} for {} changed files ({})", args.describe_command(), n, listed),
+    };
+    if status::is_terminal(true) && std::env::var_os("NO_COLOR").is_none() {
+        status_eprintln!("\x1b[1m{}\x1b[0m", line);
+    } else {
+        status_eprintln!("{}", line);
+    }
+}
+
+/// With --confirm, show what is about to run and wait for a yes or no from stdin, taking no
+/// answer within --confirm-timeout as no
+fn confirm(shared: &Shared, args: &Args, paths: &[String]) -> bool {
+    let (tx, rx) = channel();
+    *shared.answer.lock().unwrap() = Some(tx);
+    // Not status messages - the prompt is answered at the terminal, so always goes to it
+    eprintln!("{} changed paths:", paths.len());
+    for path in paths {
+        eprintln!("  {}", path);
+    }
+    let timeout = args.confirm_timeout;
+    eprintln!(
+        "Run {}? [y/N] (no after {}s)",
+        args.describe_command(),
+        timeout.num_seconds()
+    );
+    let answer = rx.recv_timeout(timeout.to_std().unwrap_or_default());
+    *shared.answer.lock().unwrap() = None;
+    match answer {
+        Ok(line) if matches!(line.trim().to_lowercase().as_str(), "y" | "yes") => true,
+        Ok(_) => {
+            eprintln!("Not running");
+            false
+        }
+        Err(_) => {
+            eprintln!("No answer - not running");
+            false
+        }
+    }
+}
+
+/// Merge paths which are spellings of the same file for --canonical-paths, dropping those which
+/// no longer exist unless --keep-missing or --deletions-only is set; paths which resolve
+/// outside the watched directory (through a symlink) keep their original spelling; with
+/// --no-canonicalize, paths are spelled through the watched directory as given
+fn canonical(paths: Vec<(String, Op)>, args: &Args) -> Vec<(String, Op)> {
+    let root = args.dir();
+    let real_root = std::fs::canonicalize(&root).unwrap_or_else(|_| root.clone());
+    let mut result: IndexMap<String, Op> = IndexMap::with_capacity(paths.len());
+    for (path, op) in paths {
+        let path = match std::fs::canonicalize(&path) {
+            Ok(real) => match real.strip_prefix(&real_root) {
+                Ok(rest) => root.join(rest).to_string_lossy().to_string(),
+                Err(_) => path,
+            },
+            Err(_) if args.keep_missing || args.deletions_only => path,
+            Err(e) => {
+                debug!("Drop {} - {}", path, e);
+                continue;
+            }
+        };
+        *result.entry(path).or_insert_with(Op::empty) |= op;
+    }
+    if !args.preserve_order {
+        result.sort_keys();
+    }
+    result.into_iter().collect()
+}
+
+/// Collapse changed paths to the distinct directories containing them for --by-dir, merging
+/// the operations of each; a changed directory stands for itself
+fn by_dir(paths: Vec<(String, Op)>, args: &Args) -> Vec<(String, Op)> {
+    let root = args.dir();
+    let mut dirs: IndexMap<String, Op> = IndexMap::with_capacity(paths.len());
+    for (path, op) in paths {
+        let buf = PathBuf::from(&path);
+        let dir = match buf.parent() {
+            Some(parent) if buf != root && !buf.is_dir() => parent.to_string_lossy().to_string(),
+            _ => path,
+        };
+        *dirs.entry(dir).or_insert_with(Op::empty) |= op;
+    }
+    if !args.preserve_order {
+        dirs.sort_keys();
+    }
+    dirs.into_iter().collect()
+}
+
+/// The path to `target` relative to the deepest of `roots` containing it, or `target` itself
+/// if none does
+fn relativize(roots: &[PathBuf], target: PathBuf) -> PathBuf {
+    roots
+        .iter()
+        .filter_map(|root| {
+            relative_to(root, &target).map(|relative| (root.components().count(), relative))
+        })
+        .max_by_key(|(depth, _)| *depth)
+        .map(|(_, relative)| relative)
+        .unwrap_or(target)
+}
+
+fn relative_to(base: &Path, target: &Path) -> Option<PathBuf> {
+    if target == base {
+        // e.g. the watched directory was replaced wholesale
+        return Some(PathBuf::from("."));
+    }
+    if let Ok(relative) = Path::strip_prefix(target, base) {
+        return Some(relative.to_path_buf());
+    }
+    if cfg!(target_os = "windows") {
+        // The canonicalized base may have a verbatim \\?\ prefix the event paths lack, or
+        // either may use forward slashes, which verbatim paths do not treat as separators
+        let (base, target) = (windows_normalize(base), windows_normalize(target));
+        if let Ok(relative) = Path::strip_prefix(target.as_path(), base.as_path()) {
+            return Some(relative.to_path_buf());
+        }
+    }
+    None
+}
+
+/// Strips any verbatim prefix from a Windows path and makes all separators backslashes
+fn windows_normalize(path: &Path) -> PathBuf {
+    let st = path.to_string_lossy().replace('/', "\\");
+    let st = if let Some(unc) = st.strip_prefix(r"\\?\UNC\") {
+        format!(r"\\{}", unc)
+    } else if let Some(local) = st.strip_prefix(r"\\?\") {
+        local.to_string()
+    } else {
+        st
+    };
+    PathBuf::from(st)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use crate::args::tests::{parsed, scratch};
+
+    fn normalized(path: &str) -> String {
+        windows_normalize(Path::new(path))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn windows_normalize_strips_verbatim_prefixes() {
+        assert_eq!(r"C:\work\a.rs", normalized(r"\\?\C:\work\a.rs"));
+        assert_eq!(
+            r"\\server\share\a.rs",
+            normalized(r"\\?\UNC\server\share\a.rs")
+        );
+        assert_eq!(r"C:\work\a.rs", normalized(r"C:\work\a.rs"));
+    }
+
+    #[test]
+    fn windows_normalize_uses_backslashes() {
+        assert_eq!(r"C:\work\src\a.rs", normalized(r"C:/work\src/a.rs"));
+        assert_eq!(r"C:\work\src\a.rs", normalized(r"\\?\C:/work/src\a.rs"));
+    }
+
+    fn relative(roots: &[&str], target: &str) -> PathBuf {
+        let roots: Vec<PathBuf> = roots.iter().map(PathBuf::from).collect();
+        relativize(&roots, PathBuf::from(target))
+    }
+
+    #[test]
+    fn relativize_prefers_the_deepest_root() {
+        let roots = ["/work", "/work/vendor/lib"];
+        assert_eq!(
+            Path::new("src/a.rs"),
+            relative(&roots, "/work/vendor/lib/src/a.rs")
+        );
+        assert_eq!(
+            Path::new("vendor/b.rs"),
+            relative(&roots, "/work/vendor/b.rs")
+        );
+        let reversed = ["/work/vendor/lib", "/work"];
+        assert_eq!(
+            Path::new("src/a.rs"),
+            relative(&reversed, "/work/vendor/lib/src/a.rs")
+        );
+    }
+
+    #[test]
+    fn relativize_leaves_paths_outside_every_root_absolute() {
+        assert_eq!(
+            Path::new("/elsewhere/a.rs"),
+            relative(&["/work"], "/elsewhere/a.rs")
+        );
+        assert_eq!(
+            Path::new("/workshop/a.rs"),
+            relative(&["/work"], "/workshop/a.rs")
+        );
+    }
+
+    #[test]
+    fn relativize_maps_a_root_to_dot() {
+        assert_eq!(Path::new("."), relative(&["/work"], "/work"));
+        assert_eq!(
+            Path::new("."),
+            relative(&["/work", "/work/vendor"], "/work/vendor")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn relativize_sees_through_a_symlinked_root() {
+        let dir = scratch("symlinked-root");
+        let (real, link) = (dir.join("real"), dir.join("link"));
+        std::fs::create_dir_all(real.join("src")).unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        for flags in [&[][..], &["--no-canonicalize"][..]] {
+            let args = parsed(&[&["-d", link.to_str().unwrap()], flags].concat());
+            let roots = args.relative_roots();
+            assert_eq!(
+                Path::new("src/a.rs"),
+                relativize(&roots, link.join("src/a.rs"))
+            );
+            assert_eq!(
+                Path::new("src/a.rs"),
+                relativize(&roots, real.join("src/a.rs"))
+            );
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }